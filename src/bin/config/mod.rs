@@ -1,14 +1,16 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use clap::Arg;
 
-use crate::{PatcherConfig, WError};
+use crate::{Architecture, BuiltinsLibrary, PatcherConfig, RenameRules, SignatureCheck, WError};
 
 #[derive(Default, Clone, Debug)]
 pub struct Config {
     pub input_path: PathBuf,
     pub output_path: PathBuf,
     pub patcher_config: PatcherConfig,
+    pub unpatch: bool,
 }
 
 impl Config {
@@ -36,7 +38,23 @@ impl Config {
                     .long("builtins")
                     .takes_value(true)
                     .required(false)
-                    .help("Path to the builtins library"),
+                    .multiple_occurrences(true)
+                    .value_name("path[=module]")
+                    .help("Path to a builtins library, optionally with the module to import from"),
+            )
+            .arg(
+                Arg::new("builtins_link_file")
+                    .long("link")
+                    .takes_value(true)
+                    .required(false)
+                    .help("Path to a WebAssembly module whose builtins are copied instead of imported"),
+            )
+            .arg(
+                Arg::new("builtins_manifest_file")
+                    .long("manifest")
+                    .takes_value(true)
+                    .required(false)
+                    .help("Path to a JSON or TOML manifest listing builtins"),
             )
             .arg(
                 Arg::new("builtins_additional")
@@ -63,6 +81,120 @@ impl Config {
                     .required(false)
                     .help("Use the original name as a key in the builtins map"),
             )
+            .arg(
+                Arg::new("unpatch")
+                    .short('u')
+                    .long("unpatch")
+                    .takes_value(false)
+                    .required(false)
+                    .requires("builtins_map_file")
+                    .help("Revert the substitutions listed in the builtins map file"),
+            )
+            .arg(
+                Arg::new("import_module")
+                    .short('M')
+                    .long("import-module")
+                    .takes_value(true)
+                    .required(false)
+                    .help("Module to import builtins from (default: env)"),
+            )
+            .arg(
+                Arg::new("builtin_import_module")
+                    .long("builtin-module")
+                    .takes_value(true)
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .value_name("builtin=module")
+                    .help("Module to import a specific builtin from"),
+            )
+            .arg(
+                Arg::new("builtins_prefix")
+                    .short('p')
+                    .long("prefix")
+                    .takes_value(true)
+                    .required(false)
+                    .help("Prefix of builtins names (default: builtin_)"),
+            )
+            .arg(
+                Arg::new("builtins_suffix")
+                    .long("suffix")
+                    .takes_value(true)
+                    .required(false)
+                    .help("Suffix of builtins names"),
+            )
+            .arg(
+                Arg::new("rename")
+                    .long("rename")
+                    .takes_value(true)
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .value_name("export=import")
+                    .help("Name of the builtin replacing a specific function"),
+            )
+            .arg(
+                Arg::new("rename_regex")
+                    .long("rename-regex")
+                    .takes_value(true)
+                    .number_of_values(2)
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .value_names(&["pattern", "replacement"])
+                    .help("Substitution applied to function names before adding the prefix"),
+            )
+            .arg(
+                Arg::new("builtins_match_debug_info")
+                    .short('d')
+                    .long("debug-info")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Also look up functions to replace in the DWARF debugging information"),
+            )
+            .arg(
+                Arg::new("builtins_static_symbols")
+                    .long("static-symbols")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Also use the static symbol table of ELF builtins libraries"),
+            )
+            .arg(
+                Arg::new("builtins_symbol_version")
+                    .long("symbol-version")
+                    .takes_value(true)
+                    .required(false)
+                    .help("Only use ELF symbols with this version"),
+            )
+            .arg(
+                Arg::new("builtins_architecture")
+                    .long("arch")
+                    .takes_value(true)
+                    .required(false)
+                    .help(
+                        "Slice of fat Mach-O builtins libraries to use, or `all` (default: host)",
+                    ),
+            )
+            .arg(
+                Arg::new("remove_dead_code")
+                    .long("remove-dead-code")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Remove the functions and types that are not used any more after patching"),
+            )
+            .arg(
+                Arg::new("builtins_signatures_file")
+                    .short('s')
+                    .long("signatures")
+                    .takes_value(true)
+                    .required(false)
+                    .help("Path to a signatures manifest or C header describing the builtins"),
+            )
+            .arg(
+                Arg::new("builtins_signature_check")
+                    .long("check-signatures")
+                    .takes_value(true)
+                    .required(false)
+                    .possible_values(["warn", "fail"])
+                    .help("Check that builtins have the signature of the functions they replace"),
+            )
             .get_matches();
         let input_path = PathBuf::from(
             matches
@@ -74,24 +206,119 @@ impl Config {
                 .value_of("output_file")
                 .ok_or(WError::UsageError("Output file required"))?,
         );
-        let builtins_path = matches.value_of("builtins_file").map(PathBuf::from);
+        let builtins_libraries = matches
+            .values_of("builtins_file")
+            .unwrap_or_default()
+            .map(parse_builtins_library)
+            .collect();
+        let builtins_link_path = matches.value_of("builtins_link_file").map(PathBuf::from);
         let builtins_map_path = matches.value_of("builtins_map_file").map(PathBuf::from);
         let builtins_map_original_names = matches.is_present("builtins_map_original_names");
+        let builtins_manifest_path = matches
+            .value_of("builtins_manifest_file")
+            .map(PathBuf::from);
         let builtins_additional = matches
             .values_of("builtins_additional")
             .unwrap_or_default()
             .map(|name| name.to_string())
             .collect();
+        let builtins_import_module = matches.value_of("import_module").map(|s| s.to_string());
+        let mut builtins_import_modules = HashMap::new();
+        for builtin_module in matches
+            .values_of("builtin_import_module")
+            .unwrap_or_default()
+        {
+            let (name, module) = builtin_module.split_once('=').ok_or(WError::UsageError(
+                "Builtin modules have to be specified as builtin=module",
+            ))?;
+            builtins_import_modules.insert(name.to_string(), module.to_string());
+        }
+        let mut builtins_rename_rules = RenameRules {
+            prefix: matches.value_of("builtins_prefix").map(|s| s.to_string()),
+            suffix: matches
+                .value_of("builtins_suffix")
+                .unwrap_or_default()
+                .to_string(),
+            ..Default::default()
+        };
+        for rename in matches.values_of("rename").unwrap_or_default() {
+            let (name, import_name) = rename.split_once('=').ok_or(WError::UsageError(
+                "Renamed builtins have to be specified as export=import",
+            ))?;
+            builtins_rename_rules
+                .pairs
+                .insert(name.to_string(), import_name.to_string());
+        }
+        let rename_regex: Vec<_> = matches
+            .values_of("rename_regex")
+            .unwrap_or_default()
+            .collect();
+        for substitution in rename_regex.chunks(2) {
+            builtins_rename_rules.add_substitution(substitution[0], substitution[1])?;
+        }
+        let builtins_match_debug_info = matches.is_present("builtins_match_debug_info");
+        let builtins_static_symbols = matches.is_present("builtins_static_symbols");
+        let builtins_symbol_version = matches
+            .value_of("builtins_symbol_version")
+            .map(|s| s.to_string());
+        let builtins_architecture = match matches.value_of("builtins_architecture") {
+            None => Architecture::Host,
+            Some("all") => Architecture::All,
+            Some(name) => Architecture::Named(name.to_string()),
+        };
+        let builtins_signatures_path = matches
+            .value_of("builtins_signatures_file")
+            .map(PathBuf::from);
+        let builtins_signature_check = match matches.value_of("builtins_signature_check") {
+            None => SignatureCheck::Disabled,
+            Some("warn") => SignatureCheck::Warn,
+            Some(_) => SignatureCheck::Fail,
+        };
+        let remove_dead_code = matches.is_present("remove_dead_code");
+        let unpatch = matches.is_present("unpatch");
         let config = Config {
             input_path,
             output_path,
             patcher_config: PatcherConfig {
-                builtins_path,
+                builtins_libraries,
                 builtins_map_path,
                 builtins_map_original_names,
+                builtins_manifest_path,
                 builtins_additional,
+                builtins_import_module,
+                builtins_import_modules,
+                builtins_rename_rules,
+                builtins_match_debug_info,
+                builtins_static_symbols,
+                builtins_symbol_version,
+                builtins_architecture,
+                builtins_signatures_path,
+                builtins_signature_check,
+                builtins_link_path,
+                remove_dead_code,
             },
+            unpatch,
         };
         Ok(config)
     }
 }
+
+/// Parses a `path[=module]` builtins library argument. The argument is only split if what
+/// follows the last `=` is a module name rather than part of a path, and if the whole
+/// argument isn't the path of an existing file.
+fn parse_builtins_library(library: &str) -> BuiltinsLibrary {
+    match library.rsplit_once('=') {
+        Some((path, module))
+            if !path.is_empty()
+                && !module.is_empty()
+                && !module.contains(['/', '\\'])
+                && !Path::new(library).is_file() =>
+        {
+            BuiltinsLibrary {
+                path: PathBuf::from(path),
+                import_module: Some(module.to_string()),
+            }
+        }
+        _ => BuiltinsLibrary::new(library),
+    }
+}
@@ -6,7 +6,21 @@ use crate::config::*;
 
 fn main() -> Result<(), Error> {
     let config = Config::parse_cmdline()?;
+    if config.unpatch {
+        let unpatcher = Unpatcher::from_file(config.patcher_config, config.input_path)?;
+        unpatcher.store_to_file(config.output_path)?;
+        return Ok(());
+    }
     let patcher = Patcher::from_file(config.patcher_config, config.input_path)?;
+    for warning in patcher.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    if let Some(report) = patcher.dead_code_report() {
+        eprintln!(
+            "Removed {} functions and {} types, saving {} bytes",
+            report.functions_removed, report.types_removed, report.bytes_saved
+        );
+    }
     patcher.store_to_file(config.output_path)?;
     Ok(())
 }
@@ -0,0 +1,341 @@
+use std::convert::Infallible;
+
+use wasm_encoder::reencode::{self, Reencode};
+use wasm_encoder::{
+    CodeSection, ElementSection, ExportSection, FunctionSection, GlobalSection, ImportSection,
+    SectionId, TableSection, TagSection, TypeSection,
+};
+use wasmparser::{
+    CodeSectionReader, ElementSectionReader, ExportSectionReader, ExternalKind,
+    FunctionSectionReader, GlobalSectionReader, ImportSectionReader, TableSectionReader,
+    TagSectionReader, TypeSectionReader,
+};
+
+use crate::encoding::*;
+use crate::errors::*;
+use crate::functions_ids::*;
+use crate::functions_names::*;
+use crate::linking::is_relocatable;
+use crate::sections::*;
+
+/// What the dead code elimination removed.
+#[derive(Clone, Debug, Default)]
+pub struct DeadCodeReport {
+    pub functions_removed: usize,
+    pub types_removed: usize,
+    pub bytes_saved: usize,
+}
+
+/// Records the functions and types referenced by the items going through it.
+#[derive(Default)]
+struct References {
+    functions: Vec<u32>,
+    types: Vec<u32>,
+}
+
+impl Reencode for References {
+    type Error = Infallible;
+
+    fn function_index(&mut self, func: u32) -> Result<u32, reencode::Error> {
+        self.functions.push(func);
+        Ok(func)
+    }
+
+    fn type_index(&mut self, ty: u32) -> Result<u32, reencode::Error> {
+        self.types.push(ty);
+        Ok(ty)
+    }
+}
+
+/// Renumbers types, removed types being `None`.
+struct TypeIdsReencoder<'t> {
+    type_ids: &'t [Option<u32>],
+}
+
+impl Reencode for TypeIdsReencoder<'_> {
+    type Error = Infallible;
+
+    fn type_index(&mut self, ty: u32) -> Result<u32, reencode::Error> {
+        Ok(self.type_ids[ty as usize].expect("Reference to a removed type"))
+    }
+}
+
+/// Re-encodes the sections that can reference functions or types.
+fn reencode_section<R: Reencode<Error = Infallible>>(
+    reencoder: &mut R,
+    section: &Section,
+) -> Result<Option<Section>, Error> {
+    if section.name.is_some() {
+        return Ok(None);
+    }
+    let reader = section.reader();
+    let section = match section.id {
+        id if id == SectionId::Import as u8 => {
+            let mut import_section = ImportSection::new();
+            reencoder
+                .parse_import_section(&mut import_section, ImportSectionReader::new(reader)?)?;
+            Section::from_encoded(&import_section)?
+        }
+        id if id == SectionId::Function as u8 => {
+            let mut function_section = FunctionSection::new();
+            reencoder.parse_function_section(
+                &mut function_section,
+                FunctionSectionReader::new(reader)?,
+            )?;
+            Section::from_encoded(&function_section)?
+        }
+        id if id == SectionId::Table as u8 => {
+            let mut table_section = TableSection::new();
+            reencoder.parse_table_section(&mut table_section, TableSectionReader::new(reader)?)?;
+            Section::from_encoded(&table_section)?
+        }
+        id if id == SectionId::Tag as u8 => {
+            let mut tag_section = TagSection::new();
+            reencoder.parse_tag_section(&mut tag_section, TagSectionReader::new(reader)?)?;
+            Section::from_encoded(&tag_section)?
+        }
+        id if id == SectionId::Global as u8 => {
+            let mut global_section = GlobalSection::new();
+            reencoder
+                .parse_global_section(&mut global_section, GlobalSectionReader::new(reader)?)?;
+            Section::from_encoded(&global_section)?
+        }
+        id if id == SectionId::Element as u8 => {
+            let mut element_section = ElementSection::new();
+            reencoder
+                .parse_element_section(&mut element_section, ElementSectionReader::new(reader)?)?;
+            Section::from_encoded(&element_section)?
+        }
+        id if id == SectionId::Code as u8 => {
+            let mut code_section = CodeSection::new();
+            reencoder.parse_code_section(&mut code_section, CodeSectionReader::new(reader)?)?;
+            Section::from_encoded(&code_section)?
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(section))
+}
+
+/// Returns the functions referenced from outside the code section: exports, start function,
+/// tables, element segments and globals.
+fn root_functions(module: &Module) -> Result<Vec<u32>, Error> {
+    let mut references = References::default();
+    for section in module.sections() {
+        if section.is(SectionId::Code) {
+            continue;
+        }
+        reencode_section(&mut references, section)?;
+    }
+    let mut functions = references.functions;
+    if let Some(export_section) = module.section(SectionId::Export) {
+        for export in ExportSectionReader::new(export_section.reader())? {
+            let export = export?;
+            if let ExternalKind::Func | ExternalKind::FuncExact = export.kind {
+                functions.push(export.index);
+            }
+        }
+    }
+    if let Some(start_section) = module.section(SectionId::Start) {
+        functions.push(start_section.reader().read_var_u32()?);
+    }
+    Ok(functions)
+}
+
+/// Returns whether every function can be reached from the roots. Imported functions are
+/// always kept.
+fn reachable_functions(module: &Module) -> Result<Vec<bool>, Error> {
+    let imports_count = function_imports_count(module)?;
+    let mut reachable = vec![false; functions_space(module)? as usize];
+    for function_id in 0..imports_count {
+        reachable[function_id as usize] = true;
+    }
+    let bodies = match module.section(SectionId::Code) {
+        None => vec![],
+        Some(code_section) => CodeSectionReader::new(code_section.reader())?
+            .into_iter()
+            .collect::<Result<_, _>>()?,
+    };
+    let mut queue = root_functions(module)?;
+    while let Some(function_id) = queue.pop() {
+        match reachable.get_mut(function_id as usize) {
+            Some(true) => continue,
+            Some(reachable) => *reachable = true,
+            None => bail!(WError::ParseError),
+        }
+        let body = bodies[(function_id - imports_count) as usize].clone();
+        let mut references = References::default();
+        references.parse_function_body(&mut CodeSection::new(), body)?;
+        queue.extend(references.functions);
+    }
+    Ok(reachable)
+}
+
+/// Removes the definitions of the functions that are not kept.
+fn remove_function_bodies(module: &mut Module, kept: &[bool]) -> Result<(), WError> {
+    let imports_count = function_imports_count(module)? as usize;
+    let type_ids = function_type_ids(module)?;
+    let mut payload = vec![];
+    let kept_count = kept[imports_count..].iter().filter(|kept| **kept).count();
+    write_var_u32(&mut payload, kept_count as u32);
+    for (type_id, _) in type_ids
+        .iter()
+        .zip(&kept[imports_count..])
+        .filter(|(_, kept)| **kept)
+    {
+        write_var_u32(&mut payload, *type_id);
+    }
+    *module.section_mut(SectionId::Function).unwrap() = Section::new(SectionId::Function, payload);
+
+    let code_section = module.section_mut(SectionId::Code).unwrap();
+    let mut reader = code_section.reader();
+    let count = reader.read_var_u32()?;
+    let mut payload = vec![];
+    write_var_u32(&mut payload, kept_count as u32);
+    for idx in 0..count as usize {
+        let body = read_raw(&mut reader, |reader| {
+            let len = reader.read_var_u32()?;
+            reader.read_bytes(len as usize).map(|_| ())
+        })?;
+        if kept[imports_count + idx] {
+            payload.extend_from_slice(body);
+        }
+    }
+    code_section.payload = payload;
+    Ok(())
+}
+
+/// Returns the new index of every type, `None` for types that are not used any more.
+///
+/// Types are kept or removed by recursion group, and types referenced by kept types are
+/// kept as well.
+fn used_types(module: &Module) -> Result<Vec<Option<u32>>, Error> {
+    let type_section = match module.section(SectionId::Type) {
+        None => return Ok(vec![]),
+        Some(type_section) => type_section,
+    };
+    let rec_groups: Vec<_> = TypeSectionReader::new(type_section.reader())?
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+    let mut references = References::default();
+    for section in module.sections() {
+        reencode_section(&mut references, section)?;
+    }
+    let mut used = vec![
+        false;
+        rec_groups
+            .iter()
+            .map(|rec_group| rec_group.types().len())
+            .sum()
+    ];
+    let mut queue = references.types;
+    while let Some(type_id) = queue.pop() {
+        let mut first_type_id = 0;
+        for rec_group in &rec_groups {
+            let types_count = rec_group.types().len();
+            let range = first_type_id..first_type_id + types_count;
+            first_type_id += types_count;
+            if !range.contains(&(type_id as usize)) || used[range.start] {
+                continue;
+            }
+            used[range].iter_mut().for_each(|used| *used = true);
+            let mut references = References::default();
+            let mut type_section = TypeSection::new();
+            references.parse_recursive_type_group(type_section.ty(), rec_group.clone())?;
+            queue.extend(references.types);
+        }
+    }
+    let mut next_id = 0;
+    let type_ids = used
+        .into_iter()
+        .map(|used| {
+            used.then(|| {
+                next_id += 1;
+                next_id - 1
+            })
+        })
+        .collect();
+    Ok(type_ids)
+}
+
+fn remove_unused_types(module: &mut Module) -> Result<usize, Error> {
+    let type_ids = used_types(module)?;
+    let removed = type_ids.iter().filter(|type_id| type_id.is_none()).count();
+    if removed == 0 {
+        return Ok(0);
+    }
+    let mut reencoder = TypeIdsReencoder {
+        type_ids: &type_ids,
+    };
+    for section in module.sections_mut() {
+        if let Some(reencoded) = reencode_section(&mut reencoder, section)? {
+            *section = reencoded;
+        }
+    }
+    let type_section = module.section_mut(SectionId::Type).unwrap();
+    let mut kept_types = TypeSection::new();
+    let mut type_id = 0;
+    for rec_group in TypeSectionReader::new(type_section.reader())? {
+        let rec_group = rec_group?;
+        let types_count = rec_group.types().len();
+        if type_ids[type_id].is_some() {
+            reencoder.parse_recursive_type_group(kept_types.ty(), rec_group)?;
+        }
+        type_id += types_count;
+    }
+    *type_section = Section::from_encoded(&kept_types)?;
+    remap_type_names(module, &type_ids)?;
+    Ok(removed)
+}
+
+/// Removes the exports of the functions replaced by builtins.
+fn remove_replaced_exports(
+    module: &mut Module,
+    replaced_function_ids: &[u32],
+) -> Result<(), Error> {
+    let export_section = match module.section_mut(SectionId::Export) {
+        None => return Ok(()),
+        Some(export_section) => export_section,
+    };
+    let mut exports = ExportSection::new();
+    for export in ExportSectionReader::new(export_section.reader())? {
+        let export = export?;
+        if let ExternalKind::Func | ExternalKind::FuncExact = export.kind {
+            if replaced_function_ids.contains(&export.index) {
+                continue;
+            }
+        }
+        let kind = reencode::RoundtripReencoder.export_kind(export.kind)?;
+        exports.export(export.name, kind, export.index);
+    }
+    *export_section = Section::from_encoded(&exports)?;
+    Ok(())
+}
+
+/// Removes the functions replaced by builtins along with their exports, the functions that
+/// cannot be reached from the exports, the start function, tables and globals any more, and
+/// then the types that are not used any more.
+pub fn remove_dead_code(
+    module: &mut Module,
+    replaced_function_ids: &[u32],
+) -> Result<DeadCodeReport, Error> {
+    if is_relocatable(module) {
+        bail!(WError::UsageError(
+            "Dead code cannot be removed from relocatable objects"
+        ));
+    }
+    let size = module.to_bytes().len();
+    remove_replaced_exports(module, replaced_function_ids)?;
+    let kept = reachable_functions(module)?;
+    let functions_removed = kept.iter().filter(|kept| !**kept).count();
+    if functions_removed > 0 {
+        remove_function_bodies(module, &kept)?;
+        remap_function_ids(module, &FunctionIdsMap::compact(&kept))?;
+    }
+    let types_removed = remove_unused_types(module)?;
+    let report = DeadCodeReport {
+        functions_removed,
+        types_removed,
+        bytes_saved: size.saturating_sub(module.to_bytes().len()),
+    };
+    Ok(report)
+}
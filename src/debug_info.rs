@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use gimli::{AttributeValue, Dwarf, EndianSlice, LittleEndian, Reader};
+use wasm_encoder::SectionId;
+use wasmparser::BinaryReader;
+
+use crate::errors::*;
+use crate::functions_ids::function_imports_count;
+use crate::sections::*;
+
+/// Maps the offset of every function body within the code section, as used by DWARF
+/// addresses, to its function index.
+fn function_ids_by_code_offset(module: &Module) -> Result<HashMap<u64, u32>, WError> {
+    let code_section = match module.section(SectionId::Code) {
+        None => return Ok(HashMap::new()),
+        Some(code_section) => code_section,
+    };
+    let base_id = function_imports_count(module)?;
+    let mut reader = BinaryReader::new(&code_section.payload, 0);
+    let count = reader.read_var_u32()?;
+    let mut function_ids = HashMap::with_capacity(count as usize);
+    for idx in 0..count {
+        let len = reader.read_var_u32()? as usize;
+        function_ids.insert(reader.original_position() as u64, base_id + idx);
+        reader.read_bytes(len)?;
+    }
+    Ok(function_ids)
+}
+
+fn subprogram_name<R: Reader>(
+    dwarf: &Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    value: Option<AttributeValue<R>>,
+) -> Result<Option<String>, gimli::Error> {
+    match value {
+        None => Ok(None),
+        Some(value) => Ok(Some(
+            dwarf
+                .attr_string(unit, value)?
+                .to_string_lossy()?
+                .into_owned(),
+        )),
+    }
+}
+
+/// Returns the names of the functions described by the DWARF debugging information, if present.
+pub fn function_names(module: &Module) -> Result<Vec<(u32, String)>, Error> {
+    if module.custom_section(".debug_info").is_none() {
+        return Ok(vec![]);
+    }
+    let function_ids = function_ids_by_code_offset(module)?;
+    let dwarf = Dwarf::load(|section_id| -> Result<_, gimli::Error> {
+        let payload = module
+            .custom_section(section_id.name())
+            .map(|section| section.payload.as_slice())
+            .unwrap_or_default();
+        Ok(EndianSlice::new(payload, LittleEndian))
+    })?;
+
+    let mut names = vec![];
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_subprogram {
+                continue;
+            }
+            let low_pc = match entry.attr_value(gimli::DW_AT_low_pc)? {
+                None => continue,
+                Some(value) => dwarf.attr_address(&unit, value)?,
+            };
+            let function_id = match low_pc.and_then(|low_pc| function_ids.get(&low_pc)) {
+                None => continue,
+                Some(function_id) => *function_id,
+            };
+            if let Some(name) =
+                subprogram_name(&dwarf, &unit, entry.attr_value(gimli::DW_AT_name)?)?
+            {
+                names.push((function_id, name));
+            }
+        }
+    }
+    Ok(names)
+}
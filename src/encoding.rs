@@ -0,0 +1,49 @@
+use wasm_encoder::Encode;
+use wasmparser::{BinaryReader, BinaryReaderError};
+
+use crate::errors::*;
+
+/// Returns the bytes consumed by `f`.
+pub fn read_raw<'t, F>(reader: &mut BinaryReader<'t>, f: F) -> Result<&'t [u8], WError>
+where
+    F: FnOnce(&mut BinaryReader<'t>) -> Result<(), BinaryReaderError>,
+{
+    let mut raw_reader = reader.skip(f)?;
+    Ok(raw_reader.read_bytes(raw_reader.bytes_remaining())?)
+}
+
+/// Returns the remaining bytes of a reader.
+pub fn read_remaining<'t>(reader: &mut BinaryReader<'t>) -> Result<&'t [u8], WError> {
+    Ok(reader.read_bytes(reader.bytes_remaining())?)
+}
+
+/// Skips a signed or unsigned LEB128 value of any width.
+pub fn skip_leb(reader: &mut BinaryReader<'_>) -> Result<(), BinaryReaderError> {
+    while reader.read_u8()? & 0x80 != 0 {}
+    Ok(())
+}
+
+pub fn write_var_u32(out: &mut Vec<u8>, value: u32) {
+    value.encode(out);
+}
+
+pub fn write_name(out: &mut Vec<u8>, name: &str) {
+    name.encode(out);
+}
+
+/// Writes `value` padded to `width` bytes when it fits, so that the encoding keeps the same
+/// size as a previous padded value (as used by relocatable objects).
+pub fn write_padded_var_u32(out: &mut Vec<u8>, mut value: u32, width: usize) {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        len += 1;
+        if value != 0 || len < width {
+            out.push(byte | 0x80);
+        } else {
+            out.push(byte);
+            break;
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use std::io;
 
 pub use anyhow::{anyhow, bail, ensure, Error};
-use parity_wasm::elements;
+use wasm_encoder::reencode;
+use wasmparser::BinaryReaderError;
 
 #[allow(dead_code)]
 #[derive(Debug, thiserror::Error)]
@@ -13,7 +14,17 @@ pub enum WError {
     #[error("{0}")]
     Io(#[from] io::Error),
     #[error("{0}")]
-    WAsmError(#[from] elements::Error),
+    WAsmError(#[from] BinaryReaderError),
+    #[error("{0}")]
+    ReencodeError(#[from] reencode::Error),
+    #[error("Incompatible signature: {0}")]
+    IncompatibleSignature(String),
+    #[error("Architecture not found: {0}")]
+    ArchitectureNotFound(String),
+    #[error("Inconsistent architectures: {0}")]
+    InconsistentArchitectures(String),
+    #[error("Cannot read signatures from the debugging information of {0}")]
+    DebugInfoError(String),
     #[error("Parse error")]
     ParseError,
     #[error("Unsupported")]
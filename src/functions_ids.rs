@@ -1,105 +1,411 @@
-use parity_wasm::elements::{
-    CodeSection, ElementSection, ExportSection, FuncBody, Instruction, Instructions, Internal,
-    Module,
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use wasm_encoder::reencode::{self, Reencode};
+use wasm_encoder::{ElementSection, ExportSection, GlobalSection, SectionId, TableSection};
+use wasmparser::{
+    BinaryReader, ElementSectionReader, ExportSectionReader, ExternalKind, FunctionBody,
+    FunctionSectionReader, GlobalSectionReader, ImportSectionReader, Operator, TableSectionReader,
+    TypeRef,
 };
 
+use crate::encoding::*;
 use crate::errors::*;
+use crate::functions_names::*;
+use crate::sections::*;
 
-fn shift_function_ids_in_code_section(
-    code_section: &mut CodeSection,
+/// Old-to-new function index table.
+///
+/// Every function of the original module is moved by `shift` positions to make room for the
+/// prepended imports. References to replaced functions (calls, table entries) are redirected,
+/// while the definitions themselves (exports, names) keep their shifted index. Functions can
+/// also be removed, the remaining ones being renumbered in order.
+///
+/// References are visited in the order they appear in the module, so that individual
+/// references to a function can be told apart by their position.
+#[derive(Clone, Debug)]
+pub struct FunctionIdsMap {
     shift: u32,
-) -> Result<(), WError> {
-    let code_bodies = code_section.bodies_mut();
-    for code_body in code_bodies.iter_mut() {
-        let opcodes = code_body.code_mut().elements_mut();
-        for opcode in opcodes.iter_mut() {
-            if let Instruction::Call(function_id) = *opcode {
-                *opcode = Instruction::Call(function_id + shift)
+    ids: Vec<Option<u32>>,
+    targets: Vec<u32>,
+    /// Positions of the references to a function that are not redirected, and the number of
+    /// references to it visited so far
+    kept_references: HashMap<u32, (Vec<u32>, Cell<u32>)>,
+    /// Original function and target of every visited reference
+    references: RefCell<Vec<(u32, u32)>>,
+}
+
+impl FunctionIdsMap {
+    pub fn new(functions_count: u32, shift: u32) -> Self {
+        let targets: Vec<_> = (0..functions_count)
+            .map(|function_id| function_id + shift)
+            .collect();
+        let ids = targets.iter().copied().map(Some).collect();
+        FunctionIdsMap {
+            shift,
+            ids,
+            targets,
+            kept_references: HashMap::new(),
+            references: RefCell::default(),
+        }
+    }
+
+    /// Keeps only the functions whose flag is set.
+    pub fn compact(kept: &[bool]) -> Self {
+        let mut next_id = 0;
+        let ids: Vec<_> = kept
+            .iter()
+            .map(|kept| {
+                kept.then(|| {
+                    next_id += 1;
+                    next_id - 1
+                })
+            })
+            .collect();
+        // Removed functions cannot be referenced by the remaining ones
+        let targets = ids.iter().map(|id| id.unwrap_or(u32::MAX)).collect();
+        FunctionIdsMap {
+            shift: 0,
+            ids,
+            targets,
+            kept_references: HashMap::new(),
+            references: RefCell::default(),
+        }
+    }
+
+    pub fn replace(&mut self, before: u32, after: u32) {
+        self.targets[before as usize] = after;
+    }
+
+    /// Keeps the references to a replaced function found at the given positions among the
+    /// references to it.
+    pub fn keep_references(&mut self, function_id: u32, positions: Vec<u32>) {
+        self.kept_references
+            .insert(function_id, (positions, Cell::new(0)));
+    }
+
+    /// Returns the positions, among the references now targeting `target_id`, of those that
+    /// already targeted `function_id`.
+    pub fn references_positions(&self, target_id: u32, function_id: u32) -> Vec<u32> {
+        self.references
+            .borrow()
+            .iter()
+            .filter(|(_, target)| *target == target_id)
+            .enumerate()
+            .filter(|(_, (original, _))| *original == function_id)
+            .map(|(position, _)| position as u32)
+            .collect()
+    }
+
+    pub fn function_id(&self, function_id: u32) -> u32 {
+        self.ids
+            .get(function_id as usize)
+            .copied()
+            .flatten()
+            .unwrap_or(function_id + self.shift)
+    }
+
+    pub fn is_removed(&self, function_id: u32) -> bool {
+        matches!(self.ids.get(function_id as usize), Some(None))
+    }
+
+    pub fn target_id(&self, function_id: u32) -> u32 {
+        let mut target_id = self
+            .targets
+            .get(function_id as usize)
+            .copied()
+            .unwrap_or(function_id + self.shift);
+        if let Some((positions, count)) = self.kept_references.get(&function_id) {
+            if positions.contains(&count.get()) {
+                target_id = self.function_id(function_id);
             }
+            count.set(count.get() + 1);
         }
+        self.references.borrow_mut().push((function_id, target_id));
+        target_id
     }
-    Ok(())
 }
 
-fn shift_function_ids_in_exports_section(export_section: &mut ExportSection, shift: u32) {
-    for entry in export_section.entries_mut() {
-        let internal = entry.internal_mut();
-        if let Internal::Function(function_id) = *internal {
-            *internal = Internal::Function(function_id + shift)
+/// Translates offsets within a section whose content was rewritten.
+#[derive(Clone, Debug, Default)]
+pub struct OffsetMap {
+    breakpoints: Vec<(u32, u32)>,
+}
+
+impl OffsetMap {
+    fn add(&mut self, old_offset: usize, new_offset: usize) {
+        self.breakpoints
+            .push((old_offset as u32, new_offset as u32));
+    }
+
+    pub fn translate(&self, offset: u32) -> u32 {
+        let idx = self
+            .breakpoints
+            .partition_point(|(old_offset, _)| *old_offset <= offset);
+        match idx {
+            0 => offset,
+            idx => {
+                let (old_offset, new_offset) = self.breakpoints[idx - 1];
+                new_offset + (offset - old_offset)
+            }
         }
     }
 }
 
-fn shift_function_ids_in_elements_section(elements_section: &mut ElementSection, shift: u32) {
-    for elements_segment in elements_section.entries_mut() {
-        for function_id in elements_segment.members_mut() {
-            *function_id += shift;
+/// Redirects function references found in constant expressions (table entries, globals).
+struct FunctionTargetsReencoder<'t> {
+    function_ids_map: &'t FunctionIdsMap,
+}
+
+impl Reencode for FunctionTargetsReencoder<'_> {
+    type Error = Infallible;
+
+    fn function_index(&mut self, func: u32) -> Result<u32, reencode::Error> {
+        Ok(self.function_ids_map.target_id(func))
+    }
+}
+
+pub fn function_imports_count(module: &Module) -> Result<u32, WError> {
+    let import_section = match module.section(SectionId::Import) {
+        None => return Ok(0),
+        Some(import_section) => import_section,
+    };
+    let mut count = 0;
+    for import in ImportSectionReader::new(import_section.reader())?.into_imports() {
+        if let TypeRef::Func(_) | TypeRef::FuncExact(_) = import?.ty {
+            count += 1;
         }
     }
+    Ok(count)
 }
 
-pub fn shift_function_ids(module: &mut Module, shift: u32) -> Result<(), WError> {
-    shift_function_ids_in_code_section(module.code_section_mut().expect("No code section"), shift)?;
-    if let Some(export_section) = module.export_section_mut() {
-        shift_function_ids_in_exports_section(export_section, shift)
+/// Returns the type of every imported function.
+pub fn function_import_type_ids(module: &Module) -> Result<Vec<u32>, WError> {
+    let import_section = match module.section(SectionId::Import) {
+        None => return Ok(vec![]),
+        Some(import_section) => import_section,
+    };
+    let mut type_ids = vec![];
+    for import in ImportSectionReader::new(import_section.reader())?.into_imports() {
+        if let TypeRef::Func(type_id) | TypeRef::FuncExact(type_id) = import?.ty {
+            type_ids.push(type_id);
+        }
     }
-    if let Some(elements_section) = module.elements_section_mut() {
-        shift_function_ids_in_elements_section(elements_section, shift)
+    Ok(type_ids)
+}
+
+/// Returns the module, name and type of every imported function.
+pub fn function_imports(module: &Module) -> Result<Vec<(String, String, u32)>, WError> {
+    let import_section = match module.section(SectionId::Import) {
+        None => return Ok(vec![]),
+        Some(import_section) => import_section,
+    };
+    let mut imports = vec![];
+    for import in ImportSectionReader::new(import_section.reader())?.into_imports() {
+        let import = import?;
+        if let TypeRef::Func(type_id) | TypeRef::FuncExact(type_id) = import.ty {
+            imports.push((import.module.to_string(), import.name.to_string(), type_id));
+        }
     }
-    Ok(())
+    Ok(imports)
 }
 
-fn replace_function_id_in_code_section(code_section: &mut CodeSection, before: u32, after: u32) {
-    let code_bodies = code_section.bodies_mut();
-    for code_body in code_bodies.iter_mut() {
-        let opcodes = code_body.code_mut().elements_mut();
-        for opcode in opcodes.iter_mut() {
-            match *opcode {
-                Instruction::Call(function_id) if function_id == before => {
-                    *opcode = Instruction::Call(after)
+/// Returns the type of every function defined by the module.
+pub fn function_type_ids(module: &Module) -> Result<Vec<u32>, WError> {
+    let function_section = match module.section(SectionId::Function) {
+        None => return Ok(vec![]),
+        Some(function_section) => function_section,
+    };
+    let type_ids = FunctionSectionReader::new(function_section.reader())?
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+    Ok(type_ids)
+}
+
+pub fn functions_space(module: &Module) -> Result<u32, WError> {
+    Ok(function_imports_count(module)? + function_type_ids(module)?.len() as u32)
+}
+
+/// Rewrites a code section without changing the encoding of anything but the function
+/// references, so that relocations still point to the same operands.
+fn remap_function_ids_in_code_section(
+    payload: &[u8],
+    function_ids_map: &FunctionIdsMap,
+) -> Result<(Vec<u8>, OffsetMap), WError> {
+    let mut reader = BinaryReader::new(payload, 0);
+    let mut out = Vec::with_capacity(payload.len());
+    let mut offset_map = OffsetMap::default();
+
+    let count = reader.read_var_u32()?;
+    write_var_u32(&mut out, count);
+    for _ in 0..count {
+        let len = reader.read_var_u32()? as usize;
+        let body_offset = reader.original_position();
+        let raw_body = reader.read_bytes(len)?;
+        let function_body = FunctionBody::new(BinaryReader::new(raw_body, 0));
+        let mut operators = function_body.get_operators_reader()?;
+        let mut body = Vec::with_capacity(len);
+        let mut body_offset_map = OffsetMap::default();
+        body.extend_from_slice(&raw_body[..operators.original_position()]);
+        while !operators.eof() {
+            let instruction_offset = operators.original_position();
+            let operator = operators.read()?;
+            let raw = &raw_body[instruction_offset..operators.original_position()];
+            match operator {
+                Operator::Call { function_index }
+                | Operator::ReturnCall { function_index }
+                | Operator::RefFunc { function_index } => {
+                    body_offset_map.add(instruction_offset, body.len());
+                    body.push(raw[0]);
+                    let target_id = function_ids_map.target_id(function_index);
+                    write_padded_var_u32(&mut body, target_id, raw.len() - 1);
+                    body_offset_map.add(instruction_offset + raw.len(), body.len());
                 }
-                _ => {}
+                _ => body.extend_from_slice(raw),
             }
         }
+        write_var_u32(&mut out, body.len() as u32);
+        let new_body_offset = out.len();
+        offset_map.add(body_offset, new_body_offset);
+        for (old_offset, new_offset) in body_offset_map.breakpoints {
+            offset_map.add(
+                body_offset + old_offset as usize,
+                new_body_offset + new_offset as usize,
+            );
+        }
+        out.extend(body);
     }
+    Ok((out, offset_map))
 }
 
-fn replace_function_id_in_elements_section(
-    elements_section: &mut ElementSection,
-    before: u32,
-    after: u32,
-) {
-    for elements_segment in elements_section.entries_mut() {
-        for function_id in elements_segment.members_mut() {
-            if *function_id == before {
-                *function_id = after;
+fn remap_function_ids_in_exports_section(
+    section: &Section,
+    function_ids_map: &FunctionIdsMap,
+) -> Result<Section, Error> {
+    let mut export_section = ExportSection::new();
+    for export in ExportSectionReader::new(section.reader())? {
+        let export = export?;
+        let index = match export.kind {
+            ExternalKind::Func | ExternalKind::FuncExact => {
+                function_ids_map.function_id(export.index)
             }
-        }
+            _ => export.index,
+        };
+        let kind = reencode::RoundtripReencoder.export_kind(export.kind)?;
+        export_section.export(export.name, kind, index);
     }
+    Ok(Section::from_encoded(&export_section)?)
 }
 
-pub fn replace_function_id(module: &mut Module, before: u32, after: u32) -> Result<(), WError> {
-    if let Some(code_section) = module.code_section_mut() {
-        replace_function_id_in_code_section(code_section, before, after)
-    }
+fn remap_function_ids_in_elements_section(
+    section: &Section,
+    function_ids_map: &FunctionIdsMap,
+) -> Result<Section, Error> {
+    let mut elements_section = ElementSection::new();
+    FunctionTargetsReencoder { function_ids_map }.parse_element_section(
+        &mut elements_section,
+        ElementSectionReader::new(section.reader())?,
+    )?;
+    Ok(Section::from_encoded(&elements_section)?)
+}
 
-    if let Some(elements_section) = module.elements_section_mut() {
-        replace_function_id_in_elements_section(elements_section, before, after)
-    };
+fn remap_function_ids_in_globals_section(
+    section: &Section,
+    function_ids_map: &FunctionIdsMap,
+) -> Result<Section, Error> {
+    let mut globals_section = GlobalSection::new();
+    FunctionTargetsReencoder { function_ids_map }.parse_global_section(
+        &mut globals_section,
+        GlobalSectionReader::new(section.reader())?,
+    )?;
+    Ok(Section::from_encoded(&globals_section)?)
+}
 
-    Ok(())
+fn remap_function_ids_in_tables_section(
+    section: &Section,
+    function_ids_map: &FunctionIdsMap,
+) -> Result<Section, Error> {
+    let mut tables_section = TableSection::new();
+    FunctionTargetsReencoder { function_ids_map }.parse_table_section(
+        &mut tables_section,
+        TableSectionReader::new(section.reader())?,
+    )?;
+    Ok(Section::from_encoded(&tables_section)?)
+}
+
+fn remap_function_ids_in_start_section(
+    section: &Section,
+    function_ids_map: &FunctionIdsMap,
+) -> Result<Section, Error> {
+    let start_function_id = section.reader().read_var_u32()?;
+    let mut payload = vec![];
+    write_var_u32(&mut payload, function_ids_map.target_id(start_function_id));
+    Ok(Section::new(SectionId::Start, payload))
+}
+
+/// Remaps every function reference of the module, and returns how offsets within the code
+/// section moved.
+pub fn remap_function_ids(
+    module: &mut Module,
+    function_ids_map: &FunctionIdsMap,
+) -> Result<OffsetMap, Error> {
+    let mut code_offset_map = OffsetMap::default();
+    for section in module.sections_mut() {
+        if section.name.is_some() {
+            continue;
+        }
+        let remapped = match section.id {
+            id if id == SectionId::Code as u8 => {
+                let (payload, offset_map) =
+                    remap_function_ids_in_code_section(&section.payload, function_ids_map)?;
+                code_offset_map = offset_map;
+                Section::new(SectionId::Code, payload)
+            }
+            id if id == SectionId::Export as u8 => {
+                remap_function_ids_in_exports_section(section, function_ids_map)?
+            }
+            id if id == SectionId::Element as u8 => {
+                remap_function_ids_in_elements_section(section, function_ids_map)?
+            }
+            id if id == SectionId::Global as u8 => {
+                remap_function_ids_in_globals_section(section, function_ids_map)?
+            }
+            id if id == SectionId::Table as u8 => {
+                remap_function_ids_in_tables_section(section, function_ids_map)?
+            }
+            id if id == SectionId::Start as u8 => {
+                remap_function_ids_in_start_section(section, function_ids_map)?
+            }
+            _ => continue,
+        };
+        *section = remapped;
+    }
+    remap_function_names(module, function_ids_map)?;
+    Ok(code_offset_map)
 }
 
 #[allow(dead_code)]
-pub fn disable_function_id(module: &mut Module, function_id: u32) -> Result<(), WError> {
-    let base_id = match module.import_section() {
-        None => 0,
-        Some(import_section) => import_section.entries().len() as u32,
-    };
-    let code_section = module.code_section_mut().expect("No code section");
-    let code_bodies = code_section.bodies_mut();
-    let opcodes = Instructions::new(vec![Instruction::Unreachable, Instruction::End]);
-    let func_body = FuncBody::new(vec![], opcodes);
-    code_bodies[(function_id - base_id) as usize] = func_body;
+pub fn disable_function_id(module: &mut Module, function_id: u32) -> Result<(), Error> {
+    let base_id = function_imports_count(module)?;
+    let code_section = module
+        .section_mut(SectionId::Code)
+        .expect("No code section");
+    let mut reader = code_section.reader();
+    let mut payload = vec![];
+    let count = reader.read_var_u32()?;
+    write_var_u32(&mut payload, count);
+    for idx in 0..count {
+        let body = read_raw(&mut reader, |reader| {
+            let len = reader.read_var_u32()?;
+            reader.read_bytes(len as usize).map(|_| ())
+        })?;
+        match idx == function_id - base_id {
+            // No locals, `unreachable`, `end`
+            true => payload.extend_from_slice(&[0x03, 0x00, 0x00, 0x0b]),
+            false => payload.extend_from_slice(body),
+        }
+    }
+    code_section.payload = payload;
     Ok(())
 }
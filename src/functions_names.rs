@@ -1,16 +1,214 @@
-use parity_wasm::elements::{FunctionNameSubsection, IndexMap};
+use wasmparser::{BinaryReader, BinaryReaderError};
 
+use crate::encoding::*;
 use crate::errors::*;
+use crate::functions_ids::FunctionIdsMap;
+use crate::sections::*;
 
-pub fn prepend_function_name(
-    function_names_subsection: &mut FunctionNameSubsection,
-    name: String,
+pub const NAME_SECTION_NAME: &str = "name";
+
+const NAME_TYPE_FUNCTION: u8 = 1;
+const NAME_TYPE_LOCAL: u8 = 2;
+const NAME_TYPE_LABEL: u8 = 3;
+const NAME_TYPE_TYPE: u8 = 4;
+const NAME_TYPE_GLOBAL: u8 = 7;
+const NAME_TYPE_FIELD: u8 = 10;
+
+/// A raw subsection of the name section.
+///
+/// Only the function names, local names and label names subsections are keyed by function
+/// index. Other subsections (module name, types, globals...) are kept verbatim.
+#[derive(Clone, Debug)]
+struct NameSubsection {
+    name_type: u8,
+    payload: Vec<u8>,
+}
+
+fn skip_name_map(reader: &mut BinaryReader<'_>) -> Result<(), BinaryReaderError> {
+    let count = reader.read_var_u32()?;
+    for _ in 0..count {
+        reader.read_var_u32()?;
+        reader.skip_string()?;
+    }
+    Ok(())
+}
+
+fn parse_name_subsections(payload: &[u8]) -> Result<Vec<NameSubsection>, WError> {
+    let mut reader = BinaryReader::new(payload, 0);
+    let mut subsections = vec![];
+    while !reader.eof() {
+        let name_type = reader.read_u8()?;
+        let len = reader.read_var_u32()? as usize;
+        let payload = reader.read_bytes(len)?.to_vec();
+        subsections.push(NameSubsection { name_type, payload });
+    }
+    Ok(subsections)
+}
+
+fn serialize_name_subsections(subsections: Vec<NameSubsection>) -> Vec<u8> {
+    let mut payload = vec![];
+    for subsection in subsections {
+        payload.push(subsection.name_type);
+        write_var_u32(&mut payload, subsection.payload.len() as u32);
+        payload.extend(subsection.payload);
+    }
+    payload
+}
+
+/// Parses a name map (or an indirect name map if `indirect` is set) keyed by function index.
+/// Values are returned encoded, as they don't depend on the function numbering.
+fn parse_function_keyed_map(payload: &[u8], indirect: bool) -> Result<Vec<(u32, Vec<u8>)>, WError> {
+    let mut reader = BinaryReader::new(payload, 0);
+    let count = reader.read_var_u32()?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let function_id = reader.read_var_u32()?;
+        let value = if indirect {
+            read_raw(&mut reader, skip_name_map)?
+        } else {
+            read_raw(&mut reader, BinaryReader::skip_string)?
+        }
+        .to_vec();
+        entries.push((function_id, value));
+    }
+    Ok(entries)
+}
+
+fn serialize_function_keyed_map(mut entries: Vec<(u32, Vec<u8>)>) -> Vec<u8> {
+    entries.sort_by_key(|(function_id, _)| *function_id);
+    let mut payload = vec![];
+    write_var_u32(&mut payload, entries.len() as u32);
+    for (function_id, value) in entries {
+        write_var_u32(&mut payload, function_id);
+        payload.extend(value);
+    }
+    payload
+}
+
+fn is_function_keyed(name_type: u8) -> Option<bool> {
+    match name_type {
+        NAME_TYPE_FUNCTION => Some(false),
+        NAME_TYPE_LOCAL | NAME_TYPE_LABEL => Some(true),
+        _ => None,
+    }
+}
+
+/// Returns the entries of a name map subsection of the name section, if present.
+fn names(module: &Module, name_type: u8) -> Result<Vec<(u32, String)>, WError> {
+    let names_section = match module.custom_section(NAME_SECTION_NAME) {
+        None => return Ok(vec![]),
+        Some(names_section) => names_section,
+    };
+    let mut names = vec![];
+    for subsection in parse_name_subsections(&names_section.payload)? {
+        if subsection.name_type != name_type {
+            continue;
+        }
+        for (id, value) in parse_function_keyed_map(&subsection.payload, false)? {
+            let name = BinaryReader::new(&value, 0).read_string()?.to_string();
+            names.push((id, name));
+        }
+    }
+    Ok(names)
+}
+
+/// Returns the function names of the name section, if present.
+pub fn function_names(module: &Module) -> Result<Vec<(u32, String)>, WError> {
+    names(module, NAME_TYPE_FUNCTION)
+}
+
+/// Returns the global names of the name section, if present.
+pub fn global_names(module: &Module) -> Result<Vec<(u32, String)>, WError> {
+    names(module, NAME_TYPE_GLOBAL)
+}
+
+pub fn remap_function_names(
+    module: &mut Module,
+    function_ids_map: &FunctionIdsMap,
 ) -> Result<(), WError> {
-    let mut map_new = IndexMap::with_capacity(function_names_subsection.names().len() + 1_usize);
-    for (idx, name) in function_names_subsection.names() {
-        map_new.insert(idx + 1, name.clone());
+    let names_section = match module.custom_section_mut(NAME_SECTION_NAME) {
+        None => return Ok(()),
+        Some(names_section) => names_section,
+    };
+    let mut subsections = parse_name_subsections(&names_section.payload)?;
+    for subsection in subsections.iter_mut() {
+        let indirect = match is_function_keyed(subsection.name_type) {
+            None => continue,
+            Some(indirect) => indirect,
+        };
+        let entries = parse_function_keyed_map(&subsection.payload, indirect)?
+            .into_iter()
+            .filter(|(function_id, _)| !function_ids_map.is_removed(*function_id))
+            .map(|(function_id, value)| (function_ids_map.function_id(function_id), value))
+            .collect();
+        subsection.payload = serialize_function_keyed_map(entries);
+    }
+    names_section.payload = serialize_name_subsections(subsections);
+    Ok(())
+}
+
+/// Renumbers the subsections keyed by type index, dropping the names of removed types.
+pub fn remap_type_names(module: &mut Module, type_ids: &[Option<u32>]) -> Result<(), WError> {
+    let names_section = match module.custom_section_mut(NAME_SECTION_NAME) {
+        None => return Ok(()),
+        Some(names_section) => names_section,
+    };
+    let mut subsections = parse_name_subsections(&names_section.payload)?;
+    for subsection in subsections.iter_mut() {
+        let indirect = match subsection.name_type {
+            NAME_TYPE_TYPE => false,
+            NAME_TYPE_FIELD => true,
+            _ => continue,
+        };
+        let entries = parse_function_keyed_map(&subsection.payload, indirect)?
+            .into_iter()
+            .filter_map(|(type_id, value)| {
+                let type_id = type_ids.get(type_id as usize).copied().flatten()?;
+                Some((type_id, value))
+            })
+            .collect();
+        subsection.payload = serialize_function_keyed_map(entries);
+    }
+    names_section.payload = serialize_name_subsections(subsections);
+    Ok(())
+}
+
+pub fn insert_function_names(module: &mut Module, names: Vec<(u32, String)>) -> Result<(), WError> {
+    if module.custom_section(NAME_SECTION_NAME).is_none() {
+        module
+            .sections_mut()
+            .push(Section::new_custom(NAME_SECTION_NAME, vec![]));
+    }
+    let names_section = module
+        .custom_section_mut(NAME_SECTION_NAME)
+        .expect("Names section not present");
+    let mut subsections = parse_name_subsections(&names_section.payload)?;
+    let subsection_idx = match subsections
+        .iter()
+        .position(|subsection| subsection.name_type >= NAME_TYPE_FUNCTION)
+    {
+        Some(idx) if subsections[idx].name_type == NAME_TYPE_FUNCTION => idx,
+        idx => {
+            let idx = idx.unwrap_or(subsections.len());
+            let subsection = NameSubsection {
+                name_type: NAME_TYPE_FUNCTION,
+                payload: serialize_function_keyed_map(vec![]),
+            };
+            subsections.insert(idx, subsection);
+            idx
+        }
+    };
+    let subsection = &mut subsections[subsection_idx];
+    let mut entries = parse_function_keyed_map(&subsection.payload, false)?;
+    for (function_id, name) in names {
+        if entries.iter().any(|(idx, _)| *idx == function_id) {
+            return Err(WError::InternalError("Function name already present"));
+        }
+        let mut value = vec![];
+        write_name(&mut value, &name);
+        entries.push((function_id, value));
     }
-    map_new.insert(0, name);
-    *function_names_subsection.names_mut() = map_new;
+    subsection.payload = serialize_function_keyed_map(entries);
+    names_section.payload = serialize_name_subsections(subsections);
     Ok(())
 }
@@ -4,19 +4,37 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 
-#[cfg(test)]
-extern crate siphasher;
-
+mod dead_code;
+mod debug_info;
+mod encoding;
 mod errors;
 mod functions_ids;
 mod functions_names;
+mod linking;
+mod manifest;
 mod map;
 mod patcher;
+mod provenance;
+mod rename;
 mod sections;
+mod signatures;
+mod static_linking;
 mod symbols;
+mod unpatcher;
 
 #[cfg(test)]
 mod tests;
 
+pub use crate::dead_code::DeadCodeReport;
 pub use crate::errors::*;
+pub use crate::manifest::symbols_from_manifest;
+pub use crate::map::PatchedBuiltinsMap;
 pub use crate::patcher::*;
+pub use crate::provenance::{PatchedBuiltin, Provenance};
+pub use crate::rename::*;
+pub use crate::sections::{Module, Section};
+pub use crate::signatures::{Signature, SignatureCheck};
+pub use crate::symbols::{
+    extract_symbols, Architecture, ExtractOptions, ExtractedSymbol, ExtractedSymbols, SymbolTable,
+};
+pub use crate::unpatcher::Unpatcher;
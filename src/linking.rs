@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+
+use wasm_encoder::SectionId;
+use wasmparser::BinaryReader;
+
+use crate::encoding::*;
+use crate::errors::*;
+use crate::functions_ids::{FunctionIdsMap, OffsetMap};
+use crate::patcher::Builtin;
+use crate::sections::*;
+
+pub const LINKING_SECTION_NAME: &str = "linking";
+pub const RELOC_SECTION_PREFIX: &str = "reloc.";
+
+const LINKING_VERSION: u32 = 2;
+
+const WASM_COMDAT_INFO: u8 = 7;
+const WASM_SYMBOL_TABLE: u8 = 8;
+
+const WASM_COMDAT_FUNCTION: u8 = 1;
+
+const SYMTAB_FUNCTION: u8 = 0;
+const SYMTAB_DATA: u8 = 1;
+const SYMTAB_GLOBAL: u8 = 2;
+const SYMTAB_SECTION: u8 = 3;
+const SYMTAB_TAG: u8 = 4;
+const SYMTAB_TABLE: u8 = 5;
+
+const WASM_SYM_UNDEFINED: u32 = 0x10;
+const WASM_SYM_EXPLICIT_NAME: u32 = 0x40;
+
+const R_WASM_FUNCTION_INDEX_LEB: u8 = 0;
+const R_WASM_TABLE_INDEX_SLEB: u8 = 1;
+const R_WASM_TABLE_INDEX_I32: u8 = 2;
+const R_WASM_TABLE_INDEX_REL_SLEB: u8 = 12;
+const R_WASM_TABLE_INDEX_SLEB64: u8 = 18;
+const R_WASM_TABLE_INDEX_I64: u8 = 19;
+const R_WASM_TABLE_INDEX_REL_SLEB64: u8 = 24;
+
+/// Relocations that make a call or a table entry point to a function symbol, and have to
+/// follow a replaced function.
+fn reloc_type_targets_function(reloc_type: u8) -> bool {
+    matches!(
+        reloc_type,
+        R_WASM_FUNCTION_INDEX_LEB
+            | R_WASM_TABLE_INDEX_SLEB
+            | R_WASM_TABLE_INDEX_I32
+            | R_WASM_TABLE_INDEX_REL_SLEB
+            | R_WASM_TABLE_INDEX_SLEB64
+            | R_WASM_TABLE_INDEX_I64
+            | R_WASM_TABLE_INDEX_REL_SLEB64
+    )
+}
+
+fn reloc_type_has_addend(reloc_type: u8) -> bool {
+    matches!(reloc_type, 3..=5 | 8 | 9 | 11 | 14..=17 | 21..=23 | 25)
+}
+
+#[derive(Clone, Debug)]
+struct SymbolInfo {
+    kind: u8,
+    flags: u32,
+    function_id: Option<u32>,
+    payload: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+struct RelocEntry {
+    reloc_type: u8,
+    offset: u32,
+    index: u32,
+    addend: Vec<u8>,
+}
+
+pub fn is_relocatable(module: &Module) -> bool {
+    module
+        .custom_section(LINKING_SECTION_NAME)
+        .map(|linking_section| {
+            linking_section.reader().read_var_u32().ok() == Some(LINKING_VERSION)
+        })
+        .unwrap_or(false)
+}
+
+fn parse_symbol(reader: &mut BinaryReader<'_>) -> Result<SymbolInfo, WError> {
+    let kind = reader.read_u8()?;
+    let flags = reader.read_var_u32()?;
+    let has_name = flags & WASM_SYM_UNDEFINED == 0 || flags & WASM_SYM_EXPLICIT_NAME != 0;
+    let mut function_id = None;
+    let payload = match kind {
+        SYMTAB_FUNCTION => {
+            function_id = Some(reader.read_var_u32()?);
+            read_raw(reader, |reader| match has_name {
+                true => reader.skip_string(),
+                false => Ok(()),
+            })?
+        }
+        SYMTAB_GLOBAL | SYMTAB_TAG | SYMTAB_TABLE => read_raw(reader, |reader| {
+            reader.read_var_u32()?;
+            if has_name {
+                reader.skip_string()?;
+            }
+            Ok(())
+        })?,
+        SYMTAB_DATA => read_raw(reader, |reader| {
+            reader.skip_string()?;
+            if flags & WASM_SYM_UNDEFINED == 0 {
+                reader.read_var_u32()?;
+                skip_leb(reader)?;
+                skip_leb(reader)?;
+            }
+            Ok(())
+        })?,
+        SYMTAB_SECTION => read_raw(reader, |reader| reader.read_var_u32().map(|_| ()))?,
+        _ => return Err(WError::ParseError),
+    };
+    Ok(SymbolInfo {
+        kind,
+        flags,
+        function_id,
+        payload: payload.to_vec(),
+    })
+}
+
+fn serialize_symbol(out: &mut Vec<u8>, symbol: &SymbolInfo) {
+    out.push(symbol.kind);
+    write_var_u32(out, symbol.flags);
+    if let Some(function_id) = symbol.function_id {
+        write_var_u32(out, function_id);
+    }
+    out.extend_from_slice(&symbol.payload);
+}
+
+/// Remaps function symbols, and adds an undefined symbol for every new builtin import.
+/// Imports the module already had keep their symbol.
+/// Returns the symbols that references to replaced functions have to use instead.
+fn remap_symbol_table(
+    payload: &[u8],
+    function_ids_map: &FunctionIdsMap,
+    builtins: &[Builtin],
+) -> Result<(Vec<u8>, HashMap<u32, u32>), WError> {
+    let mut reader = BinaryReader::new(payload, 0);
+    let count = reader.read_var_u32()?;
+    let mut symbols = vec![];
+    for _ in 0..count {
+        symbols.push(parse_symbol(&mut reader)?);
+    }
+    let mut symbols_redirects = HashMap::new();
+    for builtin in builtins {
+        // Existing imports already have an undefined symbol
+        let existing_symbol_idx = symbols[..count as usize].iter().position(|symbol| {
+            builtin.existing_import
+                && symbol.flags & WASM_SYM_UNDEFINED != 0
+                && symbol
+                    .function_id
+                    .map(|function_id| function_ids_map.function_id(function_id))
+                    == builtin.import_function_id
+        });
+        let builtin_symbol_idx = existing_symbol_idx.unwrap_or(symbols.len()) as u32;
+        for (symbol_idx, symbol) in symbols.iter().enumerate() {
+            let replaced = symbol
+                .function_id
+                .is_some_and(|function_id| builtin.original_function_ids.contains(&function_id));
+            if replaced && symbol.flags & WASM_SYM_UNDEFINED == 0 {
+                symbols_redirects.insert(symbol_idx as u32, builtin_symbol_idx);
+            }
+        }
+        if existing_symbol_idx.is_none() {
+            symbols.push(SymbolInfo {
+                kind: SYMTAB_FUNCTION,
+                flags: WASM_SYM_UNDEFINED,
+                function_id: builtin.import_function_id,
+                payload: vec![],
+            });
+        }
+    }
+    let mut out = vec![];
+    write_var_u32(&mut out, symbols.len() as u32);
+    for (symbol_idx, mut symbol) in symbols.into_iter().enumerate() {
+        if symbol_idx < count as usize {
+            symbol.function_id = symbol
+                .function_id
+                .map(|function_id| function_ids_map.function_id(function_id));
+        }
+        serialize_symbol(&mut out, &symbol);
+    }
+    Ok((out, symbols_redirects))
+}
+
+fn remap_comdat_info(payload: &[u8], function_ids_map: &FunctionIdsMap) -> Result<Vec<u8>, WError> {
+    let mut reader = BinaryReader::new(payload, 0);
+    let mut out = vec![];
+    let comdats_count = reader.read_var_u32()?;
+    write_var_u32(&mut out, comdats_count);
+    for _ in 0..comdats_count {
+        let header = read_raw(&mut reader, |reader| {
+            reader.skip_string()?;
+            reader.read_var_u32().map(|_| ())
+        })?;
+        out.extend_from_slice(header);
+        let entries_count = reader.read_var_u32()?;
+        write_var_u32(&mut out, entries_count);
+        for _ in 0..entries_count {
+            let kind = reader.read_u8()?;
+            let mut index = reader.read_var_u32()?;
+            if kind == WASM_COMDAT_FUNCTION {
+                index = function_ids_map.function_id(index);
+            }
+            out.push(kind);
+            write_var_u32(&mut out, index);
+        }
+    }
+    Ok(out)
+}
+
+fn remap_linking_section(
+    module: &mut Module,
+    function_ids_map: &FunctionIdsMap,
+    builtins: &[Builtin],
+) -> Result<HashMap<u32, u32>, WError> {
+    let linking_section = module
+        .custom_section_mut(LINKING_SECTION_NAME)
+        .ok_or(WError::InternalError("Linking section not present"))?;
+    let mut reader = linking_section.reader();
+    let mut out = vec![];
+    let version = reader.read_var_u32()?;
+    write_var_u32(&mut out, version);
+    let mut symbols_redirects = HashMap::new();
+    let mut has_symbol_table = false;
+    while !reader.eof() {
+        let subsection_type = reader.read_u8()?;
+        let len = reader.read_var_u32()? as usize;
+        let subsection = reader.read_bytes(len)?;
+        let subsection = match subsection_type {
+            WASM_SYMBOL_TABLE => {
+                has_symbol_table = true;
+                let (subsection, redirects) =
+                    remap_symbol_table(subsection, function_ids_map, builtins)?;
+                symbols_redirects = redirects;
+                subsection
+            }
+            WASM_COMDAT_INFO => remap_comdat_info(subsection, function_ids_map)?,
+            _ => subsection.to_vec(),
+        };
+        out.push(subsection_type);
+        write_var_u32(&mut out, subsection.len() as u32);
+        out.extend(subsection);
+    }
+    if !has_symbol_table && !builtins.is_empty() {
+        let (subsection, redirects) = remap_symbol_table(&[0], function_ids_map, builtins)?;
+        symbols_redirects = redirects;
+        out.push(WASM_SYMBOL_TABLE);
+        write_var_u32(&mut out, subsection.len() as u32);
+        out.extend(subsection);
+    }
+    linking_section.payload = out;
+    Ok(symbols_redirects)
+}
+
+fn remap_reloc_section(
+    payload: &[u8],
+    symbols_redirects: &HashMap<u32, u32>,
+    offset_map: Option<&OffsetMap>,
+    section_idx_map: &dyn Fn(u32) -> u32,
+) -> Result<Vec<u8>, WError> {
+    let mut reader = BinaryReader::new(payload, 0);
+    let section_idx = reader.read_var_u32()?;
+    let count = reader.read_var_u32()?;
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let reloc_type = reader.read_u8()?;
+        let offset = reader.read_var_u32()?;
+        let index = reader.read_var_u32()?;
+        let addend = match reloc_type_has_addend(reloc_type) {
+            true => read_raw(&mut reader, skip_leb)?.to_vec(),
+            false => vec![],
+        };
+        entries.push(RelocEntry {
+            reloc_type,
+            offset,
+            index,
+            addend,
+        });
+    }
+    let mut out = vec![];
+    write_var_u32(&mut out, section_idx_map(section_idx));
+    write_var_u32(&mut out, count);
+    for mut entry in entries {
+        if reloc_type_targets_function(entry.reloc_type) {
+            if let Some(symbol_idx) = symbols_redirects.get(&entry.index) {
+                entry.index = *symbol_idx;
+            }
+        }
+        if let Some(offset_map) = offset_map {
+            entry.offset = offset_map.translate(entry.offset);
+        }
+        out.push(entry.reloc_type);
+        write_var_u32(&mut out, entry.offset);
+        write_var_u32(&mut out, entry.index);
+        out.extend(entry.addend);
+    }
+    Ok(out)
+}
+
+/// Keeps the symbol table and the relocations of a relocatable object consistent with the
+/// patched module, so that it can still be linked.
+///
+/// `inserted_section_idx` is the index of a section that was inserted into the module after
+/// the relocations were computed, if any. `code_offset_map` describes how the code section
+/// was rewritten.
+pub fn remap_relocatable_module(
+    module: &mut Module,
+    inserted_section_idx: Option<usize>,
+    function_ids_map: &FunctionIdsMap,
+    code_offset_map: &OffsetMap,
+    builtins: &[Builtin],
+) -> Result<(), Error> {
+    let symbols_redirects = remap_linking_section(module, function_ids_map, builtins)?;
+
+    let section_idx_map = |section_idx: u32| match inserted_section_idx {
+        Some(inserted_section_idx) if section_idx as usize >= inserted_section_idx => {
+            section_idx + 1
+        }
+        _ => section_idx,
+    };
+    let reloc_sections_idx: Vec<_> = module
+        .sections()
+        .iter()
+        .enumerate()
+        .filter(|(_, section)| {
+            section
+                .name
+                .as_deref()
+                .is_some_and(|name| name.starts_with(RELOC_SECTION_PREFIX))
+        })
+        .map(|(section_idx, _)| section_idx)
+        .collect();
+    for reloc_section_idx in reloc_sections_idx {
+        let target_idx = module.sections()[reloc_section_idx]
+            .reader()
+            .read_var_u32()?;
+        let target_is_code = module
+            .sections()
+            .get(section_idx_map(target_idx) as usize)
+            .ok_or(WError::ParseError)?
+            .is(SectionId::Code);
+        let reloc_section = &mut module.sections_mut()[reloc_section_idx];
+        reloc_section.payload = remap_reloc_section(
+            &reloc_section.payload,
+            &symbols_redirects,
+            target_is_code.then_some(code_offset_map),
+            &section_idx_map,
+        )?;
+    }
+    Ok(())
+}
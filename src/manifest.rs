@@ -0,0 +1,47 @@
+use std::fs;
+use std::path::Path;
+
+use crate::errors::*;
+use crate::signatures::SignatureEntry;
+use crate::symbols::{ExtractedSymbol, ExtractedSymbols, SymbolTable};
+
+#[derive(Deserialize)]
+struct ManifestEntry {
+    name: String,
+    import_module: Option<String>,
+    import_name: Option<String>,
+    signature: Option<SignatureEntry>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    builtins: Vec<ManifestEntry>,
+}
+
+/// Loads the builtins listed in a TOML (`.toml` extension) or JSON manifest such as
+/// `{"builtins": [{"name": "builtin_memcpy", "import_module": "libc", "signature":
+/// {"params": ["i32", "i32", "i32"], "results": ["i32"]}}]}`.
+pub fn symbols_from_manifest<P: AsRef<Path>>(path: P) -> Result<ExtractedSymbols, Error> {
+    let text = fs::read_to_string(&path)?;
+    let manifest: Manifest = match path
+        .as_ref()
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("toml") => toml::from_str(&text).map_err(|_| WError::ParseError)?,
+        _ => serde_json::from_str(&text).map_err(|_| WError::ParseError)?,
+    };
+    let mut symbols = vec![];
+    for entry in manifest.builtins {
+        let mut symbol = ExtractedSymbol::new(entry.name, SymbolTable::Manifest);
+        symbol.import_module = entry.import_module;
+        symbol.import_name = entry.import_name;
+        symbol.signature = entry
+            .signature
+            .map(|signature| signature.signature())
+            .transpose()?;
+        symbols.push(symbol);
+    }
+    Ok(symbols.into())
+}
@@ -1,24 +1,67 @@
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::path::Path;
 
 use crate::errors::*;
 
-#[derive(Clone, Debug, Default, Serialize)]
+/// Performed substitutions, keyed by import module, then by builtin name.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct PatchedBuiltinsMap {
-    pub env: HashMap<String, String>,
+    #[serde(flatten)]
+    pub modules: HashMap<String, HashMap<String, String>>,
+    /// Imports the module already had, keyed by import module, then by import name, with
+    /// the positions of the references to them that were already there
+    #[serde(
+        default,
+        rename = "$existing_imports",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub existing_imports: HashMap<String, HashMap<String, Vec<u32>>>,
+}
+
+fn with_original_names(map: &HashMap<String, String>) -> HashMap<String, String> {
+    map.values()
+        .map(|imported_name| (imported_name.clone(), imported_name.clone()))
+        .collect()
 }
 
 impl PatchedBuiltinsMap {
-    pub fn with_capacity(capacity: usize) -> Self {
-        PatchedBuiltinsMap {
-            env: HashMap::with_capacity(capacity),
-        }
+    pub fn insert(
+        &mut self,
+        module: String,
+        name: String,
+        imported_name: String,
+    ) -> Option<String> {
+        self.modules
+            .entry(module)
+            .or_default()
+            .insert(name, imported_name)
+    }
+
+    /// Records that an import was already in the module, along with its references.
+    pub fn insert_existing_import(
+        &mut self,
+        module: String,
+        imported_name: String,
+        kept_references: Vec<u32>,
+    ) {
+        self.existing_imports
+            .entry(module)
+            .or_default()
+            .insert(imported_name, kept_references);
     }
 
-    pub fn insert(&mut self, name: String, imported_name: String) -> Option<String> {
-        self.env.insert(name, imported_name)
+    pub fn existing_import(&self, module: &str, imported_name: &str) -> Option<&[u32]> {
+        self.existing_imports
+            .get(module)
+            .and_then(|imports| imports.get(imported_name))
+            .map(Vec::as_slice)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(builtins_map_path: P) -> Result<Self, WError> {
+        let json = fs::read_to_string(builtins_map_path)?;
+        serde_json::from_str(&json).map_err(|_| WError::ParseError)
     }
 
     pub fn write_to_file<P: AsRef<Path>>(
@@ -30,11 +73,14 @@ impl PatchedBuiltinsMap {
         let map = if original_names {
             self
         } else {
-            map_with_original_names = PatchedBuiltinsMap::default();
-            for imported_name in self.env.values() {
+            map_with_original_names = PatchedBuiltinsMap {
+                existing_imports: self.existing_imports.clone(),
+                ..Default::default()
+            };
+            for (module, map) in &self.modules {
                 map_with_original_names
-                    .env
-                    .insert(imported_name.clone(), imported_name.clone());
+                    .modules
+                    .insert(module.clone(), with_original_names(map));
             }
             &map_with_original_names
         };
@@ -48,16 +94,13 @@ impl PatchedBuiltinsMap {
         module: &str,
         original_names: bool,
     ) -> Result<HashMap<String, String>, Error> {
-        if module != "env" {
-            bail!(WError::UsageError("Empty module"));
-        }
+        let map = match self.modules.get(module) {
+            None => return Ok(HashMap::new()),
+            Some(map) => map,
+        };
         if original_names {
-            return Ok(self.env.clone());
-        }
-        let mut env_map_with_original_names = HashMap::new();
-        for imported_name in self.env.values() {
-            env_map_with_original_names.insert(imported_name.clone(), imported_name.clone());
+            return Ok(map.clone());
         }
-        Ok(env_map_with_original_names)
+        Ok(with_original_names(map))
     }
 }
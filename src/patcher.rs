@@ -1,68 +1,155 @@
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::{Path, PathBuf};
 
-use parity_wasm::elements::{
-    self, External, FunctionNameSubsection, ImportEntry, ImportSection, Internal, Module,
-    NameSection, Section,
-};
+use wasm_encoder::SectionId;
+use wasmparser::{ExportSectionReader, ExternalKind};
 
+use crate::dead_code::{remove_dead_code, DeadCodeReport};
+use crate::debug_info;
+use crate::encoding::*;
 use crate::errors::*;
 use crate::functions_ids::*;
 use crate::functions_names::*;
+use crate::linking::*;
+use crate::manifest::symbols_from_manifest;
 use crate::map::*;
+use crate::provenance::{module_hash, PatchedBuiltin, Provenance};
+use crate::rename::RenameRules;
 use crate::sections::*;
-use crate::symbols::{self, ExtractedSymbols};
+use crate::signatures::{self, SignatureCheck, Signatures};
+use crate::static_linking::link_builtins;
+use crate::symbols::{self, Architecture, ExtractOptions, ExtractedSymbols};
 
 pub const BUILTIN_PREFIX: &str = "builtin_";
+pub const DEFAULT_IMPORT_MODULE: &str = "env";
+
+const IMPORT_KIND_FUNCTION: u8 = 0x00;
+
+/// A library providing builtins.
+#[derive(Clone, Debug)]
+pub struct BuiltinsLibrary {
+    pub path: PathBuf,
+    /// Module to import the builtins of this library from
+    pub import_module: Option<String>,
+}
+
+impl BuiltinsLibrary {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        BuiltinsLibrary {
+            path: path.into(),
+            import_module: None,
+        }
+    }
+}
 
 #[derive(Default, Clone, Debug)]
 pub struct PatcherConfig {
-    pub builtins_path: Option<PathBuf>,
+    /// Builtins libraries; when several of them define the same builtin, the first one wins
+    pub builtins_libraries: Vec<BuiltinsLibrary>,
     pub builtins_map_path: Option<PathBuf>,
     pub builtins_map_original_names: bool,
+    /// Manifest listing builtins, in addition to the symbols of the builtins libraries
+    pub builtins_manifest_path: Option<PathBuf>,
     pub builtins_additional: Vec<String>,
+    /// Module builtins are imported from, `env` if not set
+    pub builtins_import_module: Option<String>,
+    /// Import module overrides, keyed by builtin name (without the prefix)
+    pub builtins_import_modules: HashMap<String, String>,
+    pub builtins_rename_rules: RenameRules,
+    /// Also look up functions to replace in the DWARF debugging information
+    pub builtins_match_debug_info: bool,
+    /// Also use the static symbol table (`.symtab`) of ELF builtins libraries
+    pub builtins_static_symbols: bool,
+    /// Only use ELF symbols with this version
+    pub builtins_symbol_version: Option<String>,
+    /// Slices of fat Mach-O builtins libraries to use
+    pub builtins_architecture: Architecture,
+    /// Signatures manifest or C header; the DWARF debugging information of the builtins
+    /// libraries is used for the builtins it doesn't describe
+    pub builtins_signatures_path: Option<PathBuf>,
+    pub builtins_signature_check: SignatureCheck,
+    /// WebAssembly module to copy the implementations of builtins from, instead of importing
+    /// them; it takes precedence over the builtins libraries
+    pub builtins_link_path: Option<PathBuf>,
+    /// Remove the functions and types that are not used any more after patching
+    pub remove_dead_code: bool,
+}
+
+impl PatcherConfig {
+    pub fn import_module(&self, builtin_name: &str) -> &str {
+        self.builtins_import_modules
+            .get(builtin_name)
+            .or(self.builtins_import_module.as_ref())
+            .map(|module| module.as_str())
+            .unwrap_or(DEFAULT_IMPORT_MODULE)
+    }
 }
 
 pub struct Patcher {
     pub config: PatcherConfig,
     patched_module: Module,
     patched_builtins_map: PatchedBuiltinsMap,
+    warnings: Vec<String>,
+    dead_code_report: Option<DeadCodeReport>,
 }
 
 impl Patcher {
     pub fn new(config: PatcherConfig, module: Module) -> Result<Self, Error> {
-        let symbols = match &config.builtins_path {
-            None => ExtractedSymbols::from(vec![]),
-            Some(builtins_path) => symbols::extract_symbols(builtins_path)?,
+        let options = ExtractOptions {
+            include_static_symbols: config.builtins_static_symbols,
+            required_version: config.builtins_symbol_version.clone(),
+            architecture: config.builtins_architecture.clone(),
+        };
+        let mut symbols = ExtractedSymbols::default();
+        if let Some(builtins_link_path) = &config.builtins_link_path {
+            let mut link_symbols = symbols::extract_symbols(builtins_link_path, &options)?;
+            for symbol in link_symbols.symbols.iter_mut() {
+                symbol.library = Some(builtins_link_path.clone());
+            }
+            symbols = symbols.merge_library(link_symbols);
         }
-        .merge_additional(&config.builtins_additional);
-        let builtins_names = symbols.builtins_names();
-        let (patched_module, patched_builtins_map) = patch_module(module, &builtins_names)?;
+        for library in &config.builtins_libraries {
+            let mut library_symbols = symbols::extract_symbols(&library.path, &options)?;
+            for symbol in library_symbols.symbols.iter_mut() {
+                symbol.library = Some(library.path.clone());
+                symbol.import_module = library.import_module.clone();
+            }
+            symbols = symbols.merge_library(library_symbols);
+        }
+        if let Some(builtins_manifest_path) = &config.builtins_manifest_path {
+            symbols = symbols.merge_manifest(symbols_from_manifest(builtins_manifest_path)?);
+        }
+        let symbols = symbols.merge_additional(&config.builtins_additional);
+        let (patched_module, patched_builtins_map, warnings, dead_code_report) =
+            patch_module(module, &config, &symbols)?;
         let patcher = Patcher {
             config,
             patched_module,
             patched_builtins_map,
+            warnings,
+            dead_code_report,
         };
         Ok(patcher)
     }
 
     pub fn from_bytes(config: PatcherConfig, bytes: &[u8]) -> Result<Self, Error> {
-        let module = parity_wasm::deserialize_buffer(bytes)?;
+        let module = Module::from_bytes(bytes)?;
         Self::new(config, module)
     }
 
     pub fn from_file<P: AsRef<Path>>(config: PatcherConfig, path_in: P) -> Result<Self, Error> {
-        let module = parity_wasm::deserialize_file(path_in)?;
-        Self::new(config, module)
+        let bytes = fs::read(path_in)?;
+        Self::from_bytes(config, &bytes)
     }
 
     pub fn into_bytes(self) -> Result<Vec<u8>, WError> {
-        let bytes = elements::serialize(self.patched_module)?;
-        Ok(bytes)
+        Ok(self.patched_module.to_bytes())
     }
 
     pub fn store_to_file<P: AsRef<Path>>(self, path_out: P) -> Result<(), WError> {
-        elements::serialize_to_file(path_out, self.patched_module)?;
+        fs::write(path_out, self.patched_module.to_bytes())?;
         if let Some(builtins_map_path) = self.config.builtins_map_path {
             self.patched_builtins_map
                 .write_to_file(builtins_map_path, self.config.builtins_map_original_names)?;
@@ -75,6 +162,17 @@ impl Patcher {
             .builtins_map(module, self.config.builtins_map_original_names)
     }
 
+    /// Returns the signature mismatches found when the check is in warning mode, and the
+    /// builtins defined by several libraries.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Returns what was removed by the dead code elimination, if enabled.
+    pub fn dead_code_report(&self) -> Option<&DeadCodeReport> {
+        self.dead_code_report.as_ref()
+    }
+
     pub fn patched_module(self) -> Module {
         self.patched_module
     }
@@ -83,144 +181,527 @@ impl Patcher {
 #[derive(Debug)]
 pub struct Builtin {
     pub name: String,
-    pub original_function_id: Option<u32>,
+    /// Symbol of the builtins library
+    pub symbol_name: String,
+    /// Library defining the symbol
+    pub library: Option<PathBuf>,
+    pub import_name: String,
+    pub import_module: String,
+    /// Functions replaced by the builtin
+    pub original_function_ids: Vec<u32>,
     pub function_type_id: Option<u32>,
+    pub import_function_id: Option<u32>,
+    /// The module already imports the builtin, and that import is reused
+    pub existing_import: bool,
+    /// Positions, among the references to the reused import, of those it already had
+    pub kept_references: Vec<u32>,
 }
 
 impl Builtin {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, import_name: String, import_module: String) -> Self {
         Builtin {
             name,
-            original_function_id: None,
+            symbol_name: import_name.clone(),
+            library: None,
+            import_name,
+            import_module,
+            original_function_ids: vec![],
             function_type_id: None,
+            import_function_id: None,
+            existing_import: false,
+            kept_references: vec![],
         }
     }
-
-    pub fn import_name(&self) -> String {
-        format!("{}{}", BUILTIN_PREFIX, self.name)
-    }
 }
 
-fn function_type_id_for_function_id(module: &Module, function_id: u32) -> Option<u32> {
-    let offset = module
-        .import_section()
-        .map(|import_section| import_section.entries().len() as u32)
-        .unwrap_or(0);
+fn function_type_id_for_function_id(
+    module: &Module,
+    function_id: u32,
+) -> Result<Option<u32>, WError> {
+    let offset = function_imports_count(module)?;
     if function_id < offset {
-        return None;
+        return Ok(None);
     }
-    let functions_section_type_ids = module.function_section().unwrap().entries();
-    Some(functions_section_type_ids[(function_id - offset) as usize].type_ref())
+    let functions_section_type_ids = function_type_ids(module)?;
+    Ok(functions_section_type_ids
+        .get((function_id - offset) as usize)
+        .copied())
 }
 
 fn add_function_type_id_to_builtins(
     module: &Module,
-    builtins: &mut Vec<Builtin>,
-) -> Result<(), WError> {
+    builtins: &mut [Builtin],
+) -> Result<(), Error> {
     for builtin in builtins.iter_mut() {
-        let function_type_id =
-            function_type_id_for_function_id(module, builtin.original_function_id.unwrap())
+        for original_function_id in &builtin.original_function_ids {
+            let function_type_id = function_type_id_for_function_id(module, *original_function_id)?
                 .expect("Function ID not found");
-        builtin.function_type_id = Some(function_type_id);
+            match builtin.function_type_id {
+                None => builtin.function_type_id = Some(function_type_id),
+                Some(type_id) if type_id == function_type_id => {}
+                Some(_) => bail!(WError::UsageError(
+                    "Functions replaced by the same builtin have different types"
+                )),
+            }
+        }
     }
     Ok(())
 }
 
-fn retain_only_used_builtins(module: &Module, builtins: &mut Vec<Builtin>) -> Result<(), WError> {
-    let export_section = module.export_section().expect("No export section");
+pub(crate) fn exported_functions(module: &Module) -> Result<Vec<(u32, String)>, WError> {
+    let export_section = match module.section(SectionId::Export) {
+        None => return Ok(vec![]),
+        Some(export_section) => export_section,
+    };
+    let mut functions = vec![];
+    for entry in ExportSectionReader::new(export_section.reader())? {
+        let entry = entry?;
+        if let ExternalKind::Func | ExternalKind::FuncExact = entry.kind {
+            functions.push((entry.index, entry.name.to_string()));
+        }
+    }
+    Ok(functions)
+}
+
+/// Returns the functions that a symbol of the builtins library can replace, in the order of
+/// the symbols.
+///
+/// Functions are looked up by export name, by name in the name section, and optionally by
+/// name in the DWARF debugging information, so that functions that are not exported can also
+/// be replaced. A builtin replaces every function whose name maps to it. A function known
+/// under several names is only replaced by the first builtin matching one of them.
+fn find_used_builtins(
+    module: &Module,
+    config: &PatcherConfig,
+    symbols: &ExtractedSymbols,
+) -> Result<Vec<Builtin>, Error> {
+    let mut named_functions = exported_functions(module)?;
+    named_functions.extend(function_names(module)?);
+    if config.builtins_match_debug_info {
+        named_functions.extend(debug_info::function_names(module)?);
+    }
 
-    for entry in export_section.entries() {
-        let internal = entry.internal();
-        let function_id = match internal {
-            Internal::Function(function_id) => *function_id,
-            _ => continue,
+    let imports_count = function_imports_count(module)?;
+    let mut candidates: HashMap<String, Vec<(String, u32)>> = HashMap::new();
+    for (function_id, name) in named_functions {
+        if function_id < imports_count {
+            continue;
+        }
+        let import_name = config.builtins_rename_rules.import_name(&name);
+        let functions = candidates.entry(import_name).or_default();
+        if !functions.iter().any(|(_, id)| *id == function_id) {
+            functions.push((name, function_id));
+        }
+    }
+
+    let mut builtins = vec![];
+    let mut claimed_function_ids = HashSet::new();
+    for symbol in &symbols.symbols {
+        let functions: Vec<_> = match candidates.remove(&symbol.name) {
+            None => continue,
+            Some(functions) => functions
+                .into_iter()
+                .filter(|(_, function_id)| claimed_function_ids.insert(*function_id))
+                .collect(),
+        };
+        if functions.is_empty() {
+            continue;
+        }
+        let name = functions[0].0.clone();
+        // Explicit overrides of the configuration take precedence over the manifest
+        let import_module = match (
+            config.builtins_import_modules.get(&name),
+            &symbol.import_module,
+        ) {
+            (None, Some(import_module)) => import_module.clone(),
+            _ => config.import_module(&name).to_string(),
         };
-        let field = entry.field();
-        for builtin in builtins.iter_mut() {
-            if field == builtin.name {
-                assert!(builtin.original_function_id.is_none());
-                builtin.original_function_id = Some(function_id);
-                break;
+        let mut builtin = Builtin::new(name, symbol.name.clone(), import_module);
+        if let Some(import_name) = &symbol.import_name {
+            builtin.import_name = import_name.clone();
+        }
+        builtin.library = symbol.library.clone();
+        builtin.original_function_ids = functions
+            .into_iter()
+            .map(|(_, function_id)| function_id)
+            .collect();
+        builtins.push(builtin);
+    }
+    Ok(builtins)
+}
+
+/// Compares the signatures of the builtins with the types of the functions they replace.
+/// Returns the mismatches if they only have to be reported as warnings.
+fn check_signatures(
+    module: &Module,
+    config: &PatcherConfig,
+    symbols: &ExtractedSymbols,
+    builtins: &[Builtin],
+) -> Result<Vec<String>, Error> {
+    if config.builtins_signature_check == SignatureCheck::Disabled || builtins.is_empty() {
+        return Ok(vec![]);
+    }
+    // WebAssembly builtins libraries and manifests carry the types of the functions, other
+    // libraries may describe them in their debugging information
+    if config.builtins_signatures_path.is_none()
+        && !symbols.has_signatures()
+        && config.builtins_libraries.is_empty()
+    {
+        bail!(WError::UsageError(
+            "Checking signatures requires a builtins library or a signatures file"
+        ));
+    }
+    let mut signatures = match &config.builtins_signatures_path {
+        Some(signatures_path) => signatures::load_signatures(signatures_path)?,
+        None => Signatures::new(),
+    };
+    signatures.extend(symbols.signatures());
+    let lookup = |signatures: &Signatures, builtin: &Builtin| {
+        signatures
+            .get(&builtin.symbol_name)
+            .or_else(|| signatures.get(&builtin.import_name))
+            .or_else(|| signatures.get(&builtin.name))
+            .cloned()
+    };
+    let mut libraries_signatures: HashMap<&Path, Signatures> = HashMap::new();
+    let function_signatures = signatures::function_signatures(module)?;
+    let mut warnings = vec![];
+    for builtin in builtins {
+        let expected = match (lookup(&signatures, builtin), &builtin.library) {
+            (Some(expected), _) => expected,
+            (None, None) => continue,
+            (None, Some(library)) => {
+                let library_signatures = match libraries_signatures.entry(library) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let library_signatures =
+                            match signatures::signatures_from_debug_info(library) {
+                                Ok(library_signatures) => library_signatures,
+                                // Builtins of this library are then left unchecked
+                                Err(err)
+                                    if config.builtins_signature_check == SignatureCheck::Warn =>
+                                {
+                                    warnings.push(err.to_string());
+                                    Signatures::new()
+                                }
+                                Err(err) => return Err(err),
+                            };
+                        entry.insert(library_signatures)
+                    }
+                };
+                match lookup(library_signatures, builtin) {
+                    None => continue,
+                    Some(expected) => expected,
+                }
             }
+        };
+        let found = function_signatures
+            .get(builtin.function_type_id.unwrap() as usize)
+            .and_then(Option::as_ref)
+            .ok_or(WError::ParseError)?;
+        if &expected == found {
+            continue;
+        }
+        let message = format!(
+            "`{}` has signature {}, but replaces `{}` with signature {}",
+            builtin.import_name, expected, builtin.name, found
+        );
+        match config.builtins_signature_check {
+            SignatureCheck::Fail => bail!(WError::IncompatibleSignature(message)),
+            _ => warnings.push(message),
         }
     }
+    Ok(warnings)
+}
 
-    builtins.retain(|builtin| builtin.original_function_id.is_some());
-    Ok(())
+/// Reports the builtins defined by several libraries, and which library was used.
+fn shadowed_builtins(symbols: &ExtractedSymbols, builtins: &[Builtin]) -> Vec<String> {
+    let mut reports = vec![];
+    for builtin in builtins {
+        // Symbols that don't come from a library, such as manifest entries, are not reported
+        let (symbol, library) = match symbols
+            .symbols
+            .iter()
+            .find(|symbol| symbol.name == builtin.symbol_name)
+        {
+            Some(symbol) if !symbol.shadowed_libraries.is_empty() => match &symbol.library {
+                Some(library) => (symbol, library),
+                None => continue,
+            },
+            _ => continue,
+        };
+        let shadowed: Vec<_> = symbol
+            .shadowed_libraries
+            .iter()
+            .map(|library| format!("`{}`", library.display()))
+            .collect();
+        reports.push(format!(
+            "`{}` is defined by several libraries, using `{}` over {}",
+            builtin.symbol_name,
+            library.display(),
+            shadowed.join(", ")
+        ));
+    }
+    reports
+}
+
+/// Adds an empty import section at its place in the section order, if the module doesn't
+/// have one yet.
+fn add_import_section_if_missing(module: &mut Module) -> Result<Option<usize>, WError> {
+    if module.section(SectionId::Import).is_some() {
+        return Ok(None);
+    }
+    let mut payload = vec![];
+    write_var_u32(&mut payload, 0);
+    let import_section_idx = module.insert_section(Section::new(SectionId::Import, payload));
+    Ok(Some(import_section_idx))
 }
 
-fn add_import_section_if_missing(module: &mut Module) -> Result<(), WError> {
-    if module.import_section().is_some() {
-        return Ok(());
+fn prepend_builtins_to_import_section(
+    module: &mut Module,
+    builtins: &[Builtin],
+) -> Result<(), WError> {
+    let new_builtins: Vec<_> = builtins
+        .iter()
+        .filter(|builtin| !builtin.existing_import)
+        .collect();
+    let import_section = module.section_mut(SectionId::Import).unwrap();
+    let mut reader = import_section.reader();
+    let count = reader.read_var_u32()?;
+    let mut payload = vec![];
+    write_var_u32(&mut payload, count + new_builtins.len() as u32);
+    for builtin in new_builtins.into_iter().rev() {
+        write_name(&mut payload, &builtin.import_module);
+        write_name(&mut payload, &builtin.import_name);
+        payload.push(IMPORT_KIND_FUNCTION);
+        write_var_u32(&mut payload, builtin.function_type_id.unwrap());
     }
-    let import_section = ImportSection::with_entries(vec![]);
-    let import_section_idx = find_type_section_idx(module).unwrap() + 1;
-    module
-        .sections_mut()
-        .insert(import_section_idx, Section::Import(import_section));
+    payload.extend_from_slice(read_remaining(&mut reader)?);
+    import_section.payload = payload;
     Ok(())
 }
 
-fn prepend_builtin_to_import_section(module: &mut Module, builtin: &Builtin) -> Result<(), WError> {
-    let import_name = builtin.import_name();
-    let external = External::Function(builtin.function_type_id.unwrap());
-    let import_entry = ImportEntry::new("env".to_string(), import_name, external);
-    module
-        .import_section_mut()
-        .unwrap()
-        .entries_mut()
-        .insert(0, import_entry);
+fn prepend_builtins_to_names_section(
+    module: &mut Module,
+    builtins: &[Builtin],
+) -> Result<(), Error> {
+    let names = builtins
+        .iter()
+        .filter(|builtin| !builtin.existing_import)
+        .map(|builtin| {
+            (
+                builtin.import_function_id.unwrap(),
+                builtin.import_name.clone(),
+            )
+        })
+        .collect();
+    insert_function_names(module, names)?;
     Ok(())
 }
 
-fn prepend_builtin_to_names_section(module: &mut Module, builtin: &Builtin) -> Result<(), Error> {
-    let import_name = builtin.import_name();
-    if module.names_section().is_none() {
-        let sections = module.sections_mut();
-        let function_names_subsection = FunctionNameSubsection::default();
-        let name_section = NameSection::new(None, Some(function_names_subsection), None);
-        sections.push(Section::Name(name_section));
-    }
-    let names_section = module
-        .names_section_mut()
-        .expect("Names section not present");
-    let function_names_subsection = match names_section.functions_mut() {
-        Some(function_names_subsection) => function_names_subsection,
-        _ => bail!(WError::InternalError("Unexpected names section")),
+/// Finds the builtins the module already imports with the same module and name, so that
+/// these imports can be reused. Such imports must have the type of the replaced functions.
+fn find_existing_imports(module: &Module, builtins: &mut [Builtin]) -> Result<(), Error> {
+    let imports = function_imports(module)?;
+    let type_signatures = signatures::function_signatures(module)?;
+    let signature = |type_id: u32| {
+        type_signatures
+            .get(type_id as usize)
+            .and_then(Option::as_ref)
+            .ok_or(WError::ParseError)
     };
-    prepend_function_name(function_names_subsection, import_name)?;
+    for builtin in builtins.iter_mut() {
+        let (import_function_id, import_type_id) =
+            match imports
+                .iter()
+                .enumerate()
+                .find(|(_, (import_module, import_name, _))| {
+                    *import_module == builtin.import_module && *import_name == builtin.import_name
+                }) {
+                None => continue,
+                Some((import_function_id, (_, _, import_type_id))) => {
+                    (import_function_id as u32, *import_type_id)
+                }
+            };
+        let expected = signature(import_type_id)?;
+        let found = signature(builtin.function_type_id.unwrap())?;
+        if expected != found {
+            bail!(WError::IncompatibleSignature(format!(
+                "`{}.{}` is already imported with signature {}, but replaces `{}` with signature {}",
+                builtin.import_module, builtin.import_name, expected, builtin.name, found
+            )));
+        }
+        builtin.import_function_id = Some(import_function_id);
+        builtin.existing_import = true;
+    }
     Ok(())
 }
 
 fn patch_module(
     module: Module,
-    builtins_names: &[&str],
-) -> Result<(Module, PatchedBuiltinsMap), Error> {
-    let mut module = module
-        .parse_names()
-        .map_err(|_| WError::InternalError("Unable to parse names"))?;
+    config: &PatcherConfig,
+    symbols: &ExtractedSymbols,
+) -> Result<
+    (
+        Module,
+        PatchedBuiltinsMap,
+        Vec<String>,
+        Option<DeadCodeReport>,
+    ),
+    Error,
+> {
+    let mut module = module;
+
+    let mut builtins = find_used_builtins(&module, config, symbols)?;
+
+    // Functions replaced by a previous run are left as they are
+    let provenance = Provenance::from_module(&module)?;
+    let mut patched_builtins_map = PatchedBuiltinsMap::default();
+    if let Some(provenance) = &provenance {
+        builtins.retain(|builtin| !provenance.is_patched(&builtin.name));
+        for builtin in &provenance.builtins {
+            if let Some(import_module) = &builtin.import_module {
+                patched_builtins_map.insert(
+                    import_module.clone(),
+                    builtin.name.clone(),
+                    builtin.import_name.clone(),
+                );
+                if builtin.existing_import {
+                    patched_builtins_map.insert_existing_import(
+                        import_module.clone(),
+                        builtin.import_name.clone(),
+                        builtin.kept_references.clone(),
+                    );
+                }
+            }
+        }
+        if builtins.is_empty() {
+            return Ok((module, patched_builtins_map, vec![], None));
+        }
+    }
+    let input_hash = match provenance {
+        Some(_) => None,
+        None => Some(module_hash(&module)),
+    };
 
-    let mut builtins: Vec<_> = builtins_names
+    add_function_type_id_to_builtins(&module, &mut builtins)?;
+    let mut warnings = check_signatures(&module, config, symbols, &builtins)?;
+    warnings.extend(shadowed_builtins(symbols, &builtins));
+    let replaced_function_ids: Vec<_> = builtins
         .iter()
-        .map(|x| Builtin::new(x.to_string()))
+        .flat_map(|builtin| builtin.original_function_ids.iter().copied())
         .collect();
 
-    retain_only_used_builtins(&module, &mut builtins)?;
-    add_function_type_id_to_builtins(&module, &mut builtins)?;
+    // Builtins implemented by the module to link are copied instead of being imported
+    let mut patched_builtins = vec![];
+    if let Some(builtins_link_path) = &config.builtins_link_path {
+        let (linked_builtins, imported_builtins): (Vec<_>, Vec<_>) = builtins
+            .into_iter()
+            .partition(|builtin| builtin.library.as_ref() == Some(builtins_link_path));
+        link_builtins(&mut module, builtins_link_path, &linked_builtins)?;
+        patched_builtins.extend(linked_builtins.into_iter().map(|builtin| PatchedBuiltin {
+            name: builtin.name,
+            import_module: None,
+            import_name: builtin.symbol_name,
+            existing_import: false,
+            kept_references: vec![],
+        }));
+        builtins = imported_builtins;
+    }
+
+    // New builtins are prepended to the import section in order, so the last one ends up
+    // first, and existing imports are shifted along with the other functions
+    find_existing_imports(&module, &mut builtins)?;
+    let builtins_count = builtins
+        .iter()
+        .filter(|builtin| !builtin.existing_import)
+        .count() as u32;
+    let mut function_ids_map = FunctionIdsMap::new(functions_space(&module)?, builtins_count);
+    let mut builtin_idx = 0;
+    for builtin in builtins.iter_mut() {
+        let import_function_id = match builtin.import_function_id {
+            Some(import_function_id) => function_ids_map.function_id(import_function_id),
+            None => {
+                builtin_idx += 1;
+                builtins_count - builtin_idx
+            }
+        };
+        builtin.import_function_id = Some(import_function_id);
+        for original_function_id in &builtin.original_function_ids {
+            function_ids_map.replace(*original_function_id, import_function_id);
+        }
+    }
+    let relocatable = is_relocatable(&module);
+    let code_offset_map = remap_function_ids(&mut module, &function_ids_map)?;
+
+    // Existing imports were shifted by the new ones, and the references they already had
+    // have to be told apart from the redirected ones to revert the substitution
+    for builtin in builtins
+        .iter_mut()
+        .filter(|builtin| builtin.existing_import)
+    {
+        let import_function_id = builtin.import_function_id.unwrap();
+        builtin.kept_references = function_ids_map
+            .references_positions(import_function_id, import_function_id - builtins_count);
+    }
+    patched_builtins.extend(builtins.iter().map(|builtin| PatchedBuiltin {
+        name: builtin.name.clone(),
+        import_module: Some(builtin.import_module.clone()),
+        import_name: builtin.import_name.clone(),
+        existing_import: builtin.existing_import,
+        kept_references: builtin.kept_references.clone(),
+    }));
+
+    let new_imports = builtins.iter().any(|builtin| !builtin.existing_import);
+    let inserted_section_idx = if new_imports {
+        add_import_section_if_missing(&mut module)?
+    } else {
+        None
+    };
+    if relocatable {
+        remap_relocatable_module(
+            &mut module,
+            inserted_section_idx,
+            &function_ids_map,
+            &code_offset_map,
+            &builtins,
+        )?;
+    }
+    if new_imports {
+        prepend_builtins_to_import_section(&mut module, &builtins)?;
+        prepend_builtins_to_names_section(&mut module, &builtins)?;
+    }
+
+    // Replaced functions are only kept alive by their exports
+    let dead_code_report = match config.remove_dead_code {
+        false => None,
+        true => {
+            let replaced_function_ids: Vec<_> = replaced_function_ids
+                .into_iter()
+                .map(|function_id| function_ids_map.function_id(function_id))
+                .collect();
+            Some(remove_dead_code(&mut module, &replaced_function_ids)?)
+        }
+    };
 
-    add_import_section_if_missing(&mut module)?;
-    for (builtin_idx, builtin) in builtins.iter_mut().enumerate() {
-        prepend_builtin_to_import_section(&mut module, builtin)?;
-        prepend_builtin_to_names_section(&mut module, builtin)?;
-        shift_function_ids(&mut module, 1)?;
-        let original_function_id = builtin.original_function_id.unwrap() + builtin_idx as u32 + 1;
-        let new_function_id = 0;
-        replace_function_id(&mut module, original_function_id, new_function_id)?;
+    // Linking tools would concatenate the sections of several objects; patching an object
+    // again reuses the builtins imports it already has instead
+    if !relocatable && !patched_builtins.is_empty() {
+        let mut provenance = provenance.unwrap_or_else(|| Provenance::new(input_hash.unwrap()));
+        provenance.record(patched_builtins);
+        provenance.dead_code_removed |= dead_code_report.is_some();
+        provenance.store(&mut module)?;
     }
 
-    let mut patched_builtins_map = PatchedBuiltinsMap::with_capacity(builtins.len());
     for builtin in builtins {
-        patched_builtins_map.insert(builtin.name.clone(), builtin.import_name());
+        if builtin.existing_import {
+            patched_builtins_map.insert_existing_import(
+                builtin.import_module.clone(),
+                builtin.import_name.clone(),
+                builtin.kept_references,
+            );
+        }
+        patched_builtins_map.insert(builtin.import_module, builtin.name, builtin.import_name);
     }
-    Ok((module, patched_builtins_map))
+    Ok((module, patched_builtins_map, warnings, dead_code_report))
 }
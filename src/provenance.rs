@@ -0,0 +1,88 @@
+use std::hash::Hasher;
+
+use siphasher::sip128::{Hasher128, SipHasher13};
+
+use crate::errors::*;
+use crate::sections::*;
+
+pub const PROVENANCE_SECTION_NAME: &str = "wasmonkey";
+
+/// A substitution recorded in the provenance section.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatchedBuiltin {
+    /// Name of the replaced function
+    pub name: String,
+    /// Module the builtin is imported from, `None` if it was copied from a linked module
+    pub import_module: Option<String>,
+    pub import_name: String,
+    /// Whether the module already imported the builtin before it was patched
+    #[serde(default)]
+    pub existing_import: bool,
+    /// Positions, among the references to an existing import, of those it already had
+    #[serde(default)]
+    pub kept_references: Vec<u32>,
+}
+
+/// What wasmonkey did to a module, stored in the `wasmonkey` custom section.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Version of wasmonkey that last patched the module
+    pub version: String,
+    /// SipHash-1-3 of the module before it was first patched
+    pub input_hash: String,
+    /// Substitutions, in the order they were applied
+    pub builtins: Vec<PatchedBuiltin>,
+    /// Whether the replaced functions were removed as dead code
+    #[serde(default)]
+    pub dead_code_removed: bool,
+}
+
+pub fn module_hash(module: &Module) -> String {
+    let mut hasher = SipHasher13::new();
+    hasher.write(&module.to_bytes());
+    format!("{:032x}", hasher.finish128().as_u128())
+}
+
+impl Provenance {
+    pub fn new(input_hash: String) -> Self {
+        Provenance {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            input_hash,
+            builtins: vec![],
+            dead_code_removed: false,
+        }
+    }
+
+    /// Reads the provenance section of a module, if it was already patched.
+    pub fn from_module(module: &Module) -> Result<Option<Self>, WError> {
+        let section = match module.custom_section(PROVENANCE_SECTION_NAME) {
+            None => return Ok(None),
+            Some(section) => section,
+        };
+        let provenance = serde_json::from_slice(&section.payload)
+            .map_err(|_| WError::UsageError("Invalid wasmonkey section"))?;
+        Ok(Some(provenance))
+    }
+
+    pub fn is_patched(&self, name: &str) -> bool {
+        self.builtins.iter().any(|builtin| builtin.name == name)
+    }
+
+    /// Records new substitutions, made by this version.
+    pub fn record(&mut self, builtins: Vec<PatchedBuiltin>) {
+        self.version = env!("CARGO_PKG_VERSION").to_string();
+        self.builtins.extend(builtins);
+    }
+
+    /// Stores the provenance section, replacing the previous one.
+    pub fn store(&self, module: &mut Module) -> Result<(), WError> {
+        let payload = serde_json::to_vec(self).map_err(|_| WError::ParseError)?;
+        match module.custom_section_mut(PROVENANCE_SECTION_NAME) {
+            Some(section) => section.payload = payload,
+            None => module
+                .sections_mut()
+                .push(Section::new_custom(PROVENANCE_SECTION_NAME, payload)),
+        }
+        Ok(())
+    }
+}
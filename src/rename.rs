@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::errors::*;
+use crate::patcher::BUILTIN_PREFIX;
+
+/// Rules turning the name of an exported function into the name of the builtin replacing it.
+///
+/// Explicit pairs take precedence. Otherwise, regular expression substitutions are applied
+/// in order, and the prefix and suffix are added to the result.
+#[derive(Clone, Debug, Default)]
+pub struct RenameRules {
+    /// Prefix of builtins names, `builtin_` if not set
+    pub prefix: Option<String>,
+    pub suffix: String,
+    /// Explicit export name to import name pairs
+    pub pairs: HashMap<String, String>,
+    pub substitutions: Vec<(Regex, String)>,
+}
+
+impl RenameRules {
+    pub fn add_substitution(&mut self, pattern: &str, replacement: &str) -> Result<(), WError> {
+        let regex =
+            Regex::new(pattern).map_err(|_| WError::UsageError("Invalid regular expression"))?;
+        self.substitutions.push((regex, replacement.to_string()));
+        Ok(())
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(BUILTIN_PREFIX)
+    }
+
+    pub fn import_name(&self, name: &str) -> String {
+        if let Some(import_name) = self.pairs.get(name) {
+            return import_name.clone();
+        }
+        let mut name = name.to_string();
+        for (regex, replacement) in &self.substitutions {
+            name = regex.replace_all(&name, replacement.as_str()).into_owned();
+        }
+        format!("{}{}{}", self.prefix(), name, self.suffix)
+    }
+}
@@ -1,8 +1,210 @@
-use parity_wasm::elements::{Module, Section};
+use wasm_encoder::{Encode, SectionId};
+use wasmparser::BinaryReader;
 
-pub fn find_type_section_idx(module: &Module) -> Option<usize> {
-    module.sections().iter().position(|section| match section {
-        Section::Type(_) => true,
-        _ => false,
-    })
+use crate::encoding::*;
+use crate::errors::*;
+
+pub const WASM_MAGIC: &[u8] = b"\0asm";
+const WASM_VERSION: &[u8] = &[1, 0, 0, 0];
+
+/// A section of a module, kept in its serialized form.
+///
+/// Sections are only parsed by the passes that need to modify them, so that anything
+/// else, including constructs unknown to the parser, is preserved as-is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+    pub id: u8,
+    /// Name of a custom section
+    pub name: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+impl Section {
+    pub fn new(id: SectionId, payload: Vec<u8>) -> Self {
+        Section {
+            id: id as u8,
+            name: None,
+            payload,
+        }
+    }
+
+    pub fn new_custom(name: &str, payload: Vec<u8>) -> Self {
+        Section {
+            id: SectionId::Custom as u8,
+            name: Some(name.to_string()),
+            payload,
+        }
+    }
+
+    /// Builds a section from a section encoded by `wasm-encoder`.
+    pub fn from_encoded<S: wasm_encoder::Section>(section: &S) -> Result<Self, WError> {
+        let mut bytes = vec![];
+        section.encode(&mut bytes);
+        let mut reader = BinaryReader::new(&bytes, 0);
+        reader.read_var_u32()?;
+        let payload = read_remaining(&mut reader)?.to_vec();
+        Ok(Section {
+            id: section.id(),
+            name: None,
+            payload,
+        })
+    }
+
+    /// Appends the items of another section, for sections made of a vector of items.
+    pub fn extend_items(&mut self, other: &Section) -> Result<(), WError> {
+        let mut reader = self.reader();
+        let count = reader.read_var_u32()?;
+        let items = read_remaining(&mut reader)?;
+        let mut other_reader = other.reader();
+        let other_count = other_reader.read_var_u32()?;
+        let other_items = read_remaining(&mut other_reader)?;
+        let mut payload = vec![];
+        write_var_u32(&mut payload, count + other_count);
+        payload.extend_from_slice(items);
+        payload.extend_from_slice(other_items);
+        self.payload = payload;
+        Ok(())
+    }
+
+    pub fn is(&self, id: SectionId) -> bool {
+        self.id == id as u8 && self.name.is_none()
+    }
+
+    pub fn is_custom(&self, name: &str) -> bool {
+        self.name.as_deref() == Some(name)
+    }
+
+    pub fn reader(&self) -> BinaryReader<'_> {
+        BinaryReader::new(&self.payload, 0)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Module {
+    sections: Vec<Section>,
+}
+
+impl Module {
+    pub fn new(sections: Vec<Section>) -> Self {
+        Module { sections }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WError> {
+        let mut reader = BinaryReader::new(bytes, 0);
+        if reader.read_bytes(4)? != WASM_MAGIC {
+            return Err(WError::ParseError);
+        }
+        if reader.read_bytes(4)? != WASM_VERSION {
+            return Err(WError::Unsupported);
+        }
+        let mut sections = vec![];
+        while !reader.eof() {
+            let id = reader.read_u8()?;
+            let len = reader.read_var_u32()? as usize;
+            let mut payload_reader = BinaryReader::new(reader.read_bytes(len)?, 0);
+            let name = match id {
+                0 => Some(payload_reader.read_string()?.to_string()),
+                _ => None,
+            };
+            let payload = read_remaining(&mut payload_reader)?.to_vec();
+            sections.push(Section { id, name, payload });
+        }
+        Ok(Module { sections })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(WASM_MAGIC);
+        bytes.extend_from_slice(WASM_VERSION);
+        for section in &self.sections {
+            bytes.push(section.id);
+            match &section.name {
+                None => section.payload.as_slice().encode(&mut bytes),
+                Some(name) => {
+                    let mut payload = vec![];
+                    write_name(&mut payload, name);
+                    payload.extend_from_slice(&section.payload);
+                    payload.as_slice().encode(&mut bytes);
+                }
+            }
+        }
+        bytes
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    pub fn sections_mut(&mut self) -> &mut Vec<Section> {
+        &mut self.sections
+    }
+
+    pub fn section(&self, id: SectionId) -> Option<&Section> {
+        self.sections.iter().find(|section| section.is(id))
+    }
+
+    pub fn section_mut(&mut self, id: SectionId) -> Option<&mut Section> {
+        self.sections.iter_mut().find(|section| section.is(id))
+    }
+
+    /// Inserts a non-custom section, following the order required by the specification.
+    pub fn insert_section(&mut self, section: Section) -> usize {
+        let order = section_order(section.id);
+        let idx = self
+            .sections
+            .iter()
+            .rposition(|other| other.name.is_none() && section_order(other.id) < order)
+            .map_or(0, |idx| idx + 1);
+        self.sections.insert(idx, section);
+        idx
+    }
+
+    /// Appends the items of a section to the section of the module with the same id, or
+    /// inserts the section if the module doesn't have one yet.
+    pub fn extend_section(&mut self, section: Section) -> Result<(), WError> {
+        match self
+            .sections
+            .iter_mut()
+            .find(|other| other.name.is_none() && other.id == section.id)
+        {
+            Some(other) => other.extend_items(&section),
+            None => {
+                self.insert_section(section);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn custom_section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.is_custom(name))
+    }
+
+    pub fn custom_section_mut(&mut self, name: &str) -> Option<&mut Section> {
+        self.sections
+            .iter_mut()
+            .find(|section| section.is_custom(name))
+    }
+}
+
+/// Position of a non-custom section within a module.
+fn section_order(id: u8) -> u8 {
+    const ORDER: &[SectionId] = &[
+        SectionId::Type,
+        SectionId::Import,
+        SectionId::Function,
+        SectionId::Table,
+        SectionId::Memory,
+        SectionId::Tag,
+        SectionId::Global,
+        SectionId::Export,
+        SectionId::Start,
+        SectionId::Element,
+        SectionId::DataCount,
+        SectionId::Code,
+        SectionId::Data,
+    ];
+    ORDER
+        .iter()
+        .position(|other| *other as u8 == id)
+        .map_or(u8::MAX, |position| position as u8)
 }
@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use gimli::{AttributeValue, Dwarf, EndianSlice, Reader, RunTimeEndian, UnitOffset};
+use goblin::archive::Archive;
+use goblin::elf::Elf;
+use goblin::mach::{Mach, MachO, MultiArch, SingleArch};
+use goblin::Object;
+use regex::Regex;
+use wasm_encoder::SectionId;
+use wasmparser::{CompositeInnerType, FuncType, TypeSectionReader, ValType};
+
+use crate::errors::*;
+use crate::sections::*;
+
+/// What to do when a builtin doesn't have the signature of the function it replaces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignatureCheck {
+    #[default]
+    Disabled,
+    Warn,
+    Fail,
+}
+
+/// Parameter and result types of a function, as seen from WebAssembly.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Signature {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+impl From<&FuncType> for Signature {
+    fn from(func_type: &FuncType) -> Self {
+        Signature {
+            params: func_type.params().to_vec(),
+            results: func_type.results().to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |types: &[ValType]| {
+            types
+                .iter()
+                .map(|val_type| val_type.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        write!(f, "({}) -> ({})", join(&self.params), join(&self.results))
+    }
+}
+
+/// Signatures of builtins, keyed by symbol name.
+pub type Signatures = HashMap<String, Signature>;
+
+#[derive(Deserialize)]
+pub(crate) struct SignatureEntry {
+    #[serde(default)]
+    params: Vec<String>,
+    #[serde(default)]
+    results: Vec<String>,
+}
+
+fn parse_val_type(name: &str) -> Result<ValType, WError> {
+    match name {
+        "i32" => Ok(ValType::I32),
+        "i64" => Ok(ValType::I64),
+        "f32" => Ok(ValType::F32),
+        "f64" => Ok(ValType::F64),
+        "v128" => Ok(ValType::V128),
+        _ => Err(WError::UsageError(
+            "Unsupported type in signatures manifest",
+        )),
+    }
+}
+
+impl SignatureEntry {
+    pub(crate) fn signature(&self) -> Result<Signature, WError> {
+        Ok(Signature {
+            params: self
+                .params
+                .iter()
+                .map(|name| parse_val_type(name))
+                .collect::<Result<_, _>>()?,
+            results: self
+                .results
+                .iter()
+                .map(|name| parse_val_type(name))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Parses a JSON manifest such as `{"builtin_memcpy": {"params": ["i32", "i32", "i32"],
+/// "results": ["i32"]}}`.
+pub fn signatures_from_manifest(json: &str) -> Result<Signatures, WError> {
+    let entries: HashMap<String, SignatureEntry> =
+        serde_json::from_str(json).map_err(|_| WError::ParseError)?;
+    let mut signatures = Signatures::with_capacity(entries.len());
+    for (name, entry) in entries {
+        signatures.insert(name, entry.signature()?);
+    }
+    Ok(signatures)
+}
+
+const C_QUALIFIERS: &[&str] = &[
+    "const",
+    "volatile",
+    "restrict",
+    "__restrict",
+    "__restrict__",
+    "extern",
+    "static",
+    "inline",
+    "__inline",
+    "register",
+];
+
+const C_TYPE_WORDS: &[&str] = &[
+    "void", "char", "short", "int", "long", "signed", "unsigned", "float", "double", "_Bool",
+    "bool",
+];
+
+/// WebAssembly representation of a C type, as compiled for `wasm32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CType {
+    Void,
+    Value(ValType),
+    /// Types that can't be passed as a single value, such as structures
+    Unsupported,
+}
+
+fn c_type(name: &str) -> CType {
+    if name.contains('*') || name.contains('[') {
+        return CType::Value(ValType::I32);
+    }
+    let words: Vec<_> = name
+        .split_whitespace()
+        .filter(|word| !C_QUALIFIERS.contains(word))
+        .collect();
+    let val_type = match words.as_slice() {
+        ["void"] => return CType::Void,
+        ["float"] => ValType::F32,
+        ["double"] => ValType::F64,
+        ["int64_t" | "uint64_t"] => ValType::I64,
+        ["size_t" | "ssize_t" | "ptrdiff_t" | "intptr_t" | "uintptr_t" | "wchar_t" | "bool"
+        | "_Bool" | "int8_t" | "uint8_t" | "int16_t" | "uint16_t" | "int32_t" | "uint32_t"] => {
+            ValType::I32
+        }
+        ["enum", _] => ValType::I32,
+        words if !words.is_empty() && words.iter().all(|word| C_TYPE_WORDS.contains(word)) => {
+            if words.contains(&"float") || words.contains(&"double") || words.contains(&"void") {
+                return CType::Unsupported;
+            }
+            match words.iter().filter(|word| **word == "long").count() {
+                2 => ValType::I64,
+                _ => ValType::I32,
+            }
+        }
+        _ => return CType::Unsupported,
+    };
+    CType::Value(val_type)
+}
+
+/// Removes the name of a parameter declaration, if there is one.
+fn strip_parameter_name(param: &str) -> &str {
+    let param = param.trim();
+    let name_start = param
+        .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .map(|idx| idx + 1)
+        .unwrap_or(0);
+    let (c_type, name) = param.split_at(name_start);
+    if c_type.trim().is_empty() || C_TYPE_WORDS.contains(&name) {
+        return param;
+    }
+    let c_type_words: Vec<_> = c_type
+        .split_whitespace()
+        .filter(|word| !C_QUALIFIERS.contains(word))
+        .collect();
+    if c_type_words.is_empty() {
+        return param;
+    }
+    c_type
+}
+
+fn c_prototype_signature(result: &str, params: &str) -> Option<Signature> {
+    let results = match c_type(result) {
+        CType::Void => vec![],
+        CType::Value(val_type) => vec![val_type],
+        CType::Unsupported => return None,
+    };
+    let params = match params.trim() {
+        "" | "void" => vec![],
+        params => params
+            .split(',')
+            .map(|param| match c_type(strip_parameter_name(param)) {
+                CType::Value(val_type) if param.trim() != "..." => Some(val_type),
+                _ => None,
+            })
+            .collect::<Option<_>>()?,
+    };
+    Some(Signature { params, results })
+}
+
+/// Extracts the signatures of the function prototypes of a C header.
+///
+/// Types are interpreted as compiled for `wasm32`. Prototypes using types that can't be
+/// mapped to WebAssembly value types, such as structures passed by value, are ignored.
+pub fn signatures_from_c_header(header: &str) -> Signatures {
+    let comments = Regex::new(r"(?s)/\*.*?\*/|//[^\n]*").unwrap();
+    let prototype = Regex::new(r"(?s)^([\w\s\*]+?)\s*\b(\w+)\s*\(([^()]*)\)\s*$").unwrap();
+    let header = comments.replace_all(header, "");
+    let declarations: String = header
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut signatures = Signatures::new();
+    for declaration in declarations.split([';', '{', '}']) {
+        let captures = match prototype.captures(declaration.trim()) {
+            None => continue,
+            Some(captures) => captures,
+        };
+        if let Some(signature) = c_prototype_signature(&captures[1], &captures[3]) {
+            signatures.insert(captures[2].to_string(), signature);
+        }
+    }
+    signatures
+}
+
+fn dwarf_type_name<R: Reader>(
+    dwarf: &Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    entry: &gimli::DebuggingInformationEntry<'_, '_, R>,
+) -> Result<Option<String>, gimli::Error> {
+    match entry.attr_value(gimli::DW_AT_name)? {
+        None => Ok(None),
+        Some(value) => Ok(Some(
+            dwarf
+                .attr_string(unit, value)?
+                .to_string_lossy()?
+                .into_owned(),
+        )),
+    }
+}
+
+/// Resolves the WebAssembly type of a DWARF type, interpreted as compiled for `wasm32`.
+fn dwarf_val_type<R: Reader>(
+    dwarf: &Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    type_ref: Option<AttributeValue<R>>,
+) -> Result<CType, gimli::Error> {
+    let mut type_ref = type_ref;
+    // Typedefs and qualifiers can be chained, but not indefinitely
+    for _ in 0..16 {
+        let offset = match type_ref {
+            None => return Ok(CType::Void),
+            Some(AttributeValue::UnitRef(offset)) => offset,
+            Some(_) => return Ok(CType::Unsupported),
+        };
+        let entry = unit.entry(UnitOffset(offset.0))?;
+        match entry.tag() {
+            gimli::DW_TAG_pointer_type
+            | gimli::DW_TAG_reference_type
+            | gimli::DW_TAG_rvalue_reference_type
+            | gimli::DW_TAG_array_type
+            | gimli::DW_TAG_enumeration_type => return Ok(CType::Value(ValType::I32)),
+            gimli::DW_TAG_base_type => {
+                let name = dwarf_type_name(dwarf, unit, &entry)?.unwrap_or_default();
+                return Ok(c_type(&name));
+            }
+            gimli::DW_TAG_typedef => {
+                // Typedefs whose size depends on the target are resolved by name
+                let name = dwarf_type_name(dwarf, unit, &entry)?.unwrap_or_default();
+                match c_type(&name) {
+                    CType::Unsupported => {}
+                    c_type => return Ok(c_type),
+                }
+            }
+            gimli::DW_TAG_const_type
+            | gimli::DW_TAG_volatile_type
+            | gimli::DW_TAG_restrict_type
+            | gimli::DW_TAG_atomic_type => {}
+            _ => return Ok(CType::Unsupported),
+        }
+        type_ref = entry.attr_value(gimli::DW_AT_type)?;
+    }
+    Ok(CType::Unsupported)
+}
+
+fn signatures_from_dwarf<R: Reader>(dwarf: &Dwarf<R>) -> Result<Signatures, gimli::Error> {
+    let mut signatures = Signatures::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut tree = unit.entries_tree(None)?;
+        let mut children = tree.root()?.children();
+        while let Some(child) = children.next()? {
+            let entry = child.entry();
+            if entry.tag() != gimli::DW_TAG_subprogram
+                || entry.attr_value(gimli::DW_AT_declaration)?.is_some()
+            {
+                continue;
+            }
+            let name = match dwarf_type_name(dwarf, &unit, entry)? {
+                None => continue,
+                Some(name) => name,
+            };
+            let results = match dwarf_val_type(dwarf, &unit, entry.attr_value(gimli::DW_AT_type)?)?
+            {
+                CType::Void => vec![],
+                CType::Value(val_type) => vec![val_type],
+                CType::Unsupported => continue,
+            };
+            let mut params = vec![];
+            let mut supported = true;
+            let mut params_nodes = child.children();
+            while let Some(param) = params_nodes.next()? {
+                let param = param.entry();
+                match param.tag() {
+                    gimli::DW_TAG_formal_parameter => {
+                        let type_ref = param.attr_value(gimli::DW_AT_type)?;
+                        match dwarf_val_type(dwarf, &unit, type_ref)? {
+                            CType::Value(val_type) => params.push(val_type),
+                            _ => supported = false,
+                        }
+                    }
+                    gimli::DW_TAG_unspecified_parameters => supported = false,
+                    _ => {}
+                }
+            }
+            if supported {
+                signatures.insert(name, Signature { params, results });
+            }
+        }
+    }
+    Ok(signatures)
+}
+
+fn signatures_from_debug_sections(
+    debug_sections: &HashMap<String, &[u8]>,
+    endian: RunTimeEndian,
+) -> Result<Signatures, Error> {
+    let dwarf = Dwarf::load(|section_id| -> Result<_, gimli::Error> {
+        let data = debug_sections
+            .get(section_id.name())
+            .copied()
+            .unwrap_or_default();
+        Ok(EndianSlice::new(data, endian))
+    })?;
+    Ok(signatures_from_dwarf(&dwarf)?)
+}
+
+fn signatures_from_elf(elf: &Elf<'_>, buffer: &[u8]) -> Result<Signatures, Error> {
+    let mut debug_sections: HashMap<String, &[u8]> = HashMap::new();
+    for section in &elf.section_headers {
+        let name = match elf.shdr_strtab.get_at(section.sh_name) {
+            Some(name) if name.starts_with(".debug_") => name,
+            _ => continue,
+        };
+        if section.sh_type == goblin::elf::section_header::SHT_NOBITS {
+            continue;
+        }
+        let range = section.file_range().ok_or(WError::ParseError)?;
+        let data = buffer.get(range).ok_or(WError::ParseError)?;
+        debug_sections.insert(name.to_string(), data);
+    }
+    let endian = match elf.little_endian {
+        true => RunTimeEndian::Little,
+        false => RunTimeEndian::Big,
+    };
+    signatures_from_debug_sections(&debug_sections, endian)
+}
+
+fn signatures_from_macho(macho: &MachO<'_>) -> Result<Signatures, Error> {
+    let mut debug_sections: HashMap<String, &[u8]> = HashMap::new();
+    for segment in macho.segments.iter() {
+        for (section, data) in segment.sections().map_err(|_| WError::ParseError)? {
+            let name = section.name().map_err(|_| WError::ParseError)?;
+            if let Some(name) = name.strip_prefix("__debug_") {
+                debug_sections.insert(format!(".debug_{}", name), data);
+            }
+        }
+    }
+    let endian = match macho.little_endian {
+        true => RunTimeEndian::Little,
+        false => RunTimeEndian::Big,
+    };
+    signatures_from_debug_sections(&debug_sections, endian)
+}
+
+/// Reads the members of a static archive. Members without DWARF debugging information are
+/// skipped, and the first member describing a function wins.
+fn signatures_from_archive(archive: &Archive<'_>, buffer: &[u8]) -> Result<Signatures, Error> {
+    let mut signatures = Signatures::new();
+    for member in archive.members() {
+        let member_buffer = archive
+            .extract(member, buffer)
+            .map_err(|_| WError::ParseError)?;
+        let member_signatures = match Object::parse(member_buffer) {
+            Ok(Object::Elf(elf)) => signatures_from_elf(&elf, member_buffer)?,
+            Ok(Object::Mach(Mach::Binary(macho))) => signatures_from_macho(&macho)?,
+            _ => continue,
+        };
+        for (name, signature) in member_signatures {
+            signatures.entry(name).or_insert(signature);
+        }
+    }
+    Ok(signatures)
+}
+
+/// Reads every slice of a fat Mach-O binary, the first slice describing a function wins.
+fn signatures_from_fat(multi_arch: &MultiArch<'_>, buffer: &[u8]) -> Result<Signatures, Error> {
+    let mut signatures = Signatures::new();
+    for (idx, arch) in multi_arch.iter_arches().enumerate() {
+        let arch = arch.map_err(|_| WError::ParseError)?;
+        let slice_signatures = match multi_arch.get(idx).map_err(|_| WError::ParseError)? {
+            SingleArch::MachO(macho) => signatures_from_macho(&macho)?,
+            SingleArch::Archive(archive) => signatures_from_archive(&archive, arch.slice(buffer))?,
+        };
+        for (name, signature) in slice_signatures {
+            signatures.entry(name).or_insert(signature);
+        }
+    }
+    Ok(signatures)
+}
+
+/// Extracts the signatures of the functions described by the DWARF debugging information of
+/// an ELF or Mach-O builtins library, a static archive of such objects, or a fat Mach-O
+/// binary.
+pub fn signatures_from_debug_info<P: AsRef<Path>>(path: P) -> Result<Signatures, Error> {
+    let path = path.as_ref();
+    let signatures = fs::read(path)
+        .map_err(Error::from)
+        .and_then(
+            |buffer| match Object::parse(&buffer).map_err(|_| WError::ParseError)? {
+                Object::Elf(elf) => signatures_from_elf(&elf, &buffer),
+                Object::Mach(Mach::Binary(macho)) => signatures_from_macho(&macho),
+                Object::Mach(Mach::Fat(multi_arch)) => signatures_from_fat(&multi_arch, &buffer),
+                Object::Archive(archive) => signatures_from_archive(&archive, &buffer),
+                _ => bail!("only ELF and Mach-O files are supported"),
+            },
+        )
+        .map_err(|err| WError::DebugInfoError(format!("`{}`: {}", path.display(), err)))?;
+    Ok(signatures)
+}
+
+/// Loads signatures from a C header (`.h` extension) or from a JSON manifest.
+pub fn load_signatures<P: AsRef<Path>>(path: P) -> Result<Signatures, Error> {
+    let text = fs::read_to_string(&path)?;
+    match path
+        .as_ref()
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("h") => Ok(signatures_from_c_header(&text)),
+        _ => Ok(signatures_from_manifest(&text)?),
+    }
+}
+
+/// Returns the signature of every type of the type section, `None` for non-function types.
+pub fn function_signatures(module: &Module) -> Result<Vec<Option<Signature>>, WError> {
+    let type_section = match module.section(SectionId::Type) {
+        None => return Ok(vec![]),
+        Some(type_section) => type_section,
+    };
+    let mut signatures = vec![];
+    for rec_group in TypeSectionReader::new(type_section.reader())? {
+        for sub_type in rec_group?.types() {
+            let signature = match &sub_type.composite_type.inner {
+                CompositeInnerType::Func(func_type) => Some(Signature::from(func_type)),
+                _ => None,
+            };
+            signatures.push(signature);
+        }
+    }
+    Ok(signatures)
+}
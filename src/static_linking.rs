@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+use wasm_encoder::reencode::{self, Reencode};
+use wasm_encoder::{
+    CodeSection, DataSection, FunctionSection, GlobalSection, SectionId, TypeSection,
+};
+use wasmparser::{
+    CodeSectionReader, ConstExpr, DataKind, DataSectionReader, FunctionBody, Global,
+    GlobalSectionReader, GlobalType, ImportSectionReader, MemorySectionReader, MemoryType,
+    Operator, TypeRef,
+};
+
+use crate::encoding::*;
+use crate::errors::*;
+use crate::functions_ids::*;
+use crate::functions_names::*;
+use crate::linking::is_relocatable;
+use crate::patcher::{exported_functions, Builtin};
+use crate::sections::*;
+use crate::signatures::{function_signatures, Signature};
+
+const STACK_POINTER_NAME: &str = "__stack_pointer";
+
+/// Entities of the builtins module copied into the target module, in the order they are
+/// added. Indices are assigned when an entity is first referenced.
+#[derive(Default)]
+struct Copied {
+    ids: HashMap<u32, u32>,
+    queue: Vec<u32>,
+    processed: usize,
+}
+
+impl Copied {
+    fn assign(&mut self, base: u32, id: u32) -> u32 {
+        let queue = &mut self.queue;
+        *self.ids.entry(id).or_insert_with(|| {
+            queue.push(id);
+            base + queue.len() as u32 - 1
+        })
+    }
+
+    fn next(&mut self) -> Option<u32> {
+        let id = self.queue.get(self.processed).copied();
+        if id.is_some() {
+            self.processed += 1;
+        }
+        id
+    }
+}
+
+/// Copies functions of the builtins module, along with everything they reference, into the
+/// target module.
+struct Linker<'t> {
+    library: &'t Module,
+    library_signatures: Vec<Option<Signature>>,
+    library_function_type_ids: Vec<u32>,
+    library_function_imports: u32,
+    library_global_imports: u32,
+    library_stack_pointer: Option<(u32, GlobalType)>,
+    target_signatures: Vec<Option<Signature>>,
+    target_stack_pointer: Option<(u32, GlobalType)>,
+    types: HashMap<u32, u32>,
+    functions_base: u32,
+    functions: Copied,
+    globals_base: u32,
+    globals: Copied,
+    data_base: u32,
+    data: Copied,
+    uses_memory: bool,
+    uses_data_count: bool,
+    type_section: TypeSection,
+    function_section: FunctionSection,
+    code_section: CodeSection,
+    global_section: GlobalSection,
+    data_section: DataSection,
+}
+
+fn global_imports_count(module: &Module) -> Result<u32, WError> {
+    let import_section = match module.section(SectionId::Import) {
+        None => return Ok(0),
+        Some(import_section) => import_section,
+    };
+    let mut count = 0;
+    for import in ImportSectionReader::new(import_section.reader())?.into_imports() {
+        if let TypeRef::Global(_) = import?.ty {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Returns the index and the type of the global holding the shadow stack pointer, if the
+/// module has one. It is found by name, either in the imports or in the name section.
+fn stack_pointer(module: &Module) -> Result<Option<(u32, GlobalType)>, WError> {
+    let mut global_types = vec![];
+    if let Some(import_section) = module.section(SectionId::Import) {
+        for import in ImportSectionReader::new(import_section.reader())?.into_imports() {
+            let import = import?;
+            if let TypeRef::Global(global_type) = import.ty {
+                if import.name == STACK_POINTER_NAME {
+                    return Ok(Some((global_types.len() as u32, global_type)));
+                }
+                global_types.push(global_type);
+            }
+        }
+    }
+    if let Some(global_section) = module.section(SectionId::Global) {
+        for global in GlobalSectionReader::new(global_section.reader())? {
+            global_types.push(global?.ty);
+        }
+    }
+    let stack_pointer = global_names(module)?
+        .into_iter()
+        .find(|(_, name)| name == STACK_POINTER_NAME)
+        .and_then(|(global_id, _)| {
+            let global_type = global_types.get(global_id as usize)?;
+            Some((global_id, *global_type))
+        });
+    Ok(stack_pointer)
+}
+
+fn section_count(module: &Module, id: SectionId) -> Result<u32, WError> {
+    match module.section(id) {
+        None => Ok(0),
+        Some(section) => Ok(section.reader().read_var_u32()?),
+    }
+}
+
+/// Returns the initial size in bytes of the first memory, if the module has one.
+fn initial_memory_size(module: &Module) -> Result<Option<u64>, WError> {
+    let size = |memory: MemoryType| memory.initial << memory.page_size_log2.unwrap_or(16);
+    if let Some(import_section) = module.section(SectionId::Import) {
+        for import in ImportSectionReader::new(import_section.reader())?.into_imports() {
+            if let TypeRef::Memory(memory) = import?.ty {
+                return Ok(Some(size(memory)));
+            }
+        }
+    }
+    let memory = match module.section(SectionId::Memory) {
+        None => None,
+        Some(memory_section) => MemorySectionReader::new(memory_section.reader())?
+            .into_iter()
+            .next()
+            .transpose()?,
+    };
+    Ok(memory.map(size))
+}
+
+/// Returns the start and end of the active data segments of the first memory whose offset
+/// is a constant.
+fn constant_data_ranges(module: &Module) -> Result<Vec<(u64, u64)>, WError> {
+    let data_section = match module.section(SectionId::Data) {
+        None => return Ok(vec![]),
+        Some(data_section) => data_section,
+    };
+    let mut ranges = vec![];
+    for data in DataSectionReader::new(data_section.reader())? {
+        let data = data?;
+        if let DataKind::Active {
+            memory_index: 0,
+            offset_expr,
+        } = data.kind
+        {
+            if let Some(offset) = constant_offset(&offset_expr)? {
+                ranges.push((offset, offset + data.data.len() as u64));
+            }
+        }
+    }
+    Ok(ranges)
+}
+
+fn constant_offset(offset_expr: &ConstExpr<'_>) -> Result<Option<u64>, WError> {
+    let mut operators = offset_expr.get_operators_reader();
+    let offset = match operators.read()? {
+        Operator::I32Const { value } => value as u32 as u64,
+        Operator::I64Const { value } => value as u64,
+        _ => return Ok(None),
+    };
+    match operators.read()? {
+        Operator::End => Ok(Some(offset)),
+        _ => Ok(None),
+    }
+}
+
+impl Reencode for Linker<'_> {
+    type Error = WError;
+
+    fn function_index(&mut self, func: u32) -> Result<u32, reencode::Error<WError>> {
+        if func < self.library_function_imports {
+            return Err(reencode::Error::UserError(WError::UsageError(
+                "Builtins cannot call functions imported by the builtins module",
+            )));
+        }
+        Ok(self.functions.assign(self.functions_base, func))
+    }
+
+    fn type_index(&mut self, ty: u32) -> Result<u32, reencode::Error<WError>> {
+        if let Some(type_id) = self.types.get(&ty) {
+            return Ok(*type_id);
+        }
+        let signature = match self.library_signatures.get(ty as usize) {
+            Some(Some(signature)) => signature.clone(),
+            _ => return Err(reencode::Error::UserError(WError::Unsupported)),
+        };
+        let type_id = match self
+            .target_signatures
+            .iter()
+            .position(|other| other.as_ref() == Some(&signature))
+        {
+            Some(type_id) => type_id as u32,
+            None => {
+                let params = self.val_types(signature.params.clone())?;
+                let results = self.val_types(signature.results.clone())?;
+                self.type_section.ty().function(params, results);
+                self.target_signatures.push(Some(signature));
+                self.target_signatures.len() as u32 - 1
+            }
+        };
+        self.types.insert(ty, type_id);
+        Ok(type_id)
+    }
+
+    fn global_index(&mut self, global: u32) -> Result<u32, reencode::Error<WError>> {
+        // Builtins share the shadow stack of the module, as they would if they had been
+        // linked with it; a copy of the stack pointer would overwrite the module's stack
+        if let Some((stack_pointer, stack_pointer_type)) = self.library_stack_pointer {
+            if global == stack_pointer {
+                return match self.target_stack_pointer {
+                    Some((target_stack_pointer, target_stack_pointer_type))
+                        if target_stack_pointer_type == stack_pointer_type =>
+                    {
+                        Ok(target_stack_pointer)
+                    }
+                    _ => Err(reencode::Error::UserError(WError::UsageError(
+                        "Builtins use a stack pointer, but the module doesn't have a compatible one",
+                    ))),
+                };
+            }
+        }
+        if global < self.library_global_imports {
+            return Err(reencode::Error::UserError(WError::UsageError(
+                "Builtins cannot use globals imported by the builtins module",
+            )));
+        }
+        Ok(self.globals.assign(self.globals_base, global))
+    }
+
+    fn data_index(&mut self, data: u32) -> Result<u32, reencode::Error<WError>> {
+        self.uses_data_count = true;
+        Ok(self.data.assign(self.data_base, data))
+    }
+
+    fn memory_index(&mut self, memory: u32) -> Result<u32, reencode::Error<WError>> {
+        if memory != 0 {
+            return Err(reencode::Error::UserError(WError::UsageError(
+                "Builtins can only use the first memory",
+            )));
+        }
+        self.uses_memory = true;
+        Ok(memory)
+    }
+
+    fn table_index(&mut self, _table: u32) -> Result<u32, reencode::Error<WError>> {
+        Err(reencode::Error::UserError(WError::UsageError(
+            "Builtins using tables cannot be linked",
+        )))
+    }
+
+    fn element_index(&mut self, _element: u32) -> Result<u32, reencode::Error<WError>> {
+        Err(reencode::Error::UserError(WError::UsageError(
+            "Builtins using element segments cannot be linked",
+        )))
+    }
+
+    fn tag_index(&mut self, _tag: u32) -> Result<u32, reencode::Error<WError>> {
+        Err(reencode::Error::UserError(WError::UsageError(
+            "Builtins using exceptions cannot be linked",
+        )))
+    }
+}
+
+impl<'t> Linker<'t> {
+    fn new(library: &'t Module, module: &Module) -> Result<Self, WError> {
+        let linker = Linker {
+            library,
+            library_signatures: function_signatures(library)?,
+            library_function_type_ids: function_type_ids(library)?,
+            library_function_imports: function_imports_count(library)?,
+            library_global_imports: global_imports_count(library)?,
+            library_stack_pointer: stack_pointer(library)?,
+            target_signatures: function_signatures(module)?,
+            target_stack_pointer: stack_pointer(module)?,
+            types: HashMap::new(),
+            functions_base: functions_space(module)?,
+            functions: Copied::default(),
+            globals_base: global_imports_count(module)? + section_count(module, SectionId::Global)?,
+            globals: Copied::default(),
+            data_base: section_count(module, SectionId::Data)?,
+            data: Copied::default(),
+            uses_memory: false,
+            uses_data_count: false,
+            type_section: TypeSection::new(),
+            function_section: FunctionSection::new(),
+            code_section: CodeSection::new(),
+            global_section: GlobalSection::new(),
+            data_section: DataSection::new(),
+        };
+        Ok(linker)
+    }
+
+    fn function_body(&self, func: u32) -> Result<FunctionBody<'t>, WError> {
+        let code_section = self
+            .library
+            .section(SectionId::Code)
+            .ok_or(WError::ParseError)?;
+        CodeSectionReader::new(code_section.reader())?
+            .into_iter()
+            .nth((func - self.library_function_imports) as usize)
+            .ok_or(WError::ParseError)?
+            .map_err(WError::from)
+    }
+
+    fn global(&self, global: u32) -> Result<Global<'t>, WError> {
+        let global_section = self
+            .library
+            .section(SectionId::Global)
+            .ok_or(WError::ParseError)?;
+        GlobalSectionReader::new(global_section.reader())?
+            .into_iter()
+            .nth((global - self.library_global_imports) as usize)
+            .ok_or(WError::ParseError)?
+            .map_err(WError::from)
+    }
+
+    fn data_segments(&self) -> Result<Vec<wasmparser::Data<'t>>, WError> {
+        let data_section = match self.library.section(SectionId::Data) {
+            None => return Ok(vec![]),
+            Some(data_section) => data_section,
+        };
+        Ok(DataSectionReader::new(data_section.reader())?
+            .into_iter()
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Copies everything referenced so far, until no new entity gets referenced.
+    fn copy_referenced(&mut self) -> Result<(), reencode::Error<WError>> {
+        let data_segments = self.data_segments().map_err(reencode::Error::UserError)?;
+        let mut code_section = mem::take(&mut self.code_section);
+        let mut data_section = mem::take(&mut self.data_section);
+        let mut global_section = mem::take(&mut self.global_section);
+        let mut active_data_assigned = false;
+        loop {
+            let mut copied = false;
+            while let Some(func) = self.functions.next() {
+                let type_id =
+                    self.library_function_type_ids[(func - self.library_function_imports) as usize];
+                let type_id = self.type_index(type_id)?;
+                self.function_section.function(type_id);
+                let body = self
+                    .function_body(func)
+                    .map_err(reencode::Error::UserError)?;
+                self.parse_function_body(&mut code_section, body)?;
+                copied = true;
+            }
+            // Active segments initialize the memory the builtins rely on
+            if self.uses_memory && !active_data_assigned {
+                for (data_id, data) in data_segments.iter().enumerate() {
+                    if let DataKind::Active { .. } = data.kind {
+                        self.data.assign(self.data_base, data_id as u32);
+                    }
+                }
+                active_data_assigned = true;
+            }
+            while let Some(data_id) = self.data.next() {
+                let data = data_segments[data_id as usize].clone();
+                self.parse_data(&mut data_section, data)?;
+                copied = true;
+            }
+            while let Some(global_id) = self.globals.next() {
+                let global = self.global(global_id).map_err(reencode::Error::UserError)?;
+                self.parse_global(&mut global_section, global)?;
+                copied = true;
+            }
+            if !copied {
+                break;
+            }
+        }
+        self.code_section = code_section;
+        self.data_section = data_section;
+        self.global_section = global_section;
+        Ok(())
+    }
+}
+
+fn link_error(err: reencode::Error<WError>) -> Error {
+    match err {
+        reencode::Error::UserError(err) => err.into(),
+        reencode::Error::ParseError(err) => WError::WAsmError(err).into(),
+        err => anyhow!(err.to_string()),
+    }
+}
+
+/// Copies the implementations of builtins from a WebAssembly module into the module, and
+/// redirects calls to the replaced functions to them.
+pub fn link_builtins<P: AsRef<Path>>(
+    module: &mut Module,
+    library_path: P,
+    builtins: &[Builtin],
+) -> Result<(), Error> {
+    if builtins.is_empty() {
+        return Ok(());
+    }
+    if is_relocatable(module) {
+        bail!(WError::UsageError(
+            "Builtins cannot be linked into relocatable objects"
+        ));
+    }
+    let library = Module::from_bytes(&fs::read(library_path)?)?;
+    let library_functions: HashMap<_, _> = exported_functions(&library)?
+        .into_iter()
+        .map(|(function_id, name)| (name, function_id))
+        .collect();
+    let mut linker = Linker::new(&library, module)?;
+    let mut function_ids_map = FunctionIdsMap::new(linker.functions_base, 0);
+    let mut names = vec![];
+    for builtin in builtins {
+        let library_function_id = *library_functions
+            .get(&builtin.symbol_name)
+            .ok_or(WError::InternalError("Builtin not exported by the module"))?;
+        let function_id = linker
+            .function_index(library_function_id)
+            .map_err(link_error)?;
+        let expected = linker
+            .library_function_type_ids
+            .get((library_function_id - linker.library_function_imports) as usize)
+            .and_then(|type_id| linker.library_signatures.get(*type_id as usize))
+            .cloned()
+            .flatten();
+        let found = linker
+            .target_signatures
+            .get(builtin.function_type_id.unwrap() as usize)
+            .cloned()
+            .flatten();
+        if let (Some(expected), Some(found)) = (&expected, &found) {
+            if expected != found {
+                bail!(WError::IncompatibleSignature(format!(
+                    "`{}` has signature {}, but replaces `{}` with signature {}",
+                    builtin.symbol_name, expected, builtin.name, found
+                )));
+            }
+        }
+        for original_function_id in &builtin.original_function_ids {
+            function_ids_map.replace(*original_function_id, function_id);
+        }
+        if !names.iter().any(|(id, _)| *id == function_id) {
+            names.push((function_id, builtin.symbol_name.clone()));
+        }
+    }
+    linker.copy_referenced().map_err(link_error)?;
+
+    let memory_size = initial_memory_size(module)?;
+    if linker.uses_memory && memory_size.is_none() {
+        bail!(WError::UsageError(
+            "Builtins use a memory, but the module doesn't have any"
+        ));
+    }
+    let target_ranges = constant_data_ranges(module)?;
+    let linked_data = Section::from_encoded(&linker.data_section)?;
+    for data in DataSectionReader::new(linked_data.reader())? {
+        let data = data?;
+        if let DataKind::Active { offset_expr, .. } = data.kind {
+            if let Some(start) = constant_offset(&offset_expr)? {
+                let end = start + data.data.len() as u64;
+                if target_ranges
+                    .iter()
+                    .any(|(other_start, other_end)| start < *other_end && *other_start < end)
+                {
+                    bail!(WError::UsageError(
+                        "Data of the builtins module overlaps the data of the module"
+                    ));
+                }
+                // Segments are written when the module is instantiated, before it can grow
+                if memory_size.is_some_and(|memory_size| end > memory_size) {
+                    bail!(WError::UsageError(
+                        "Data of the builtins module doesn't fit in the initial memory of the module"
+                    ));
+                }
+            }
+        }
+    }
+
+    remap_function_ids(module, &function_ids_map)?;
+    if !linker.type_section.is_empty() {
+        module.extend_section(Section::from_encoded(&linker.type_section)?)?;
+    }
+    module.extend_section(Section::from_encoded(&linker.function_section)?)?;
+    if !linker.global_section.is_empty() {
+        module.extend_section(Section::from_encoded(&linker.global_section)?)?;
+    }
+    module.extend_section(Section::from_encoded(&linker.code_section)?)?;
+    if !linker.data_section.is_empty() {
+        module.extend_section(linked_data)?;
+        let data_count = linker.data_base + linker.data.queue.len() as u32;
+        let mut payload = vec![];
+        write_var_u32(&mut payload, data_count);
+        match module.section_mut(SectionId::DataCount) {
+            Some(data_count_section) => data_count_section.payload = payload,
+            None if linker.uses_data_count => {
+                module.insert_section(Section::new(SectionId::DataCount, payload));
+            }
+            None => {}
+        }
+    }
+    insert_function_names(module, names)?;
+    Ok(())
+}
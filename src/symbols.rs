@@ -1,17 +1,128 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use goblin::archive::Archive;
+use goblin::elf::section_header::SHN_UNDEF;
+use goblin::elf::sym::{self, Sym, Symtab};
+use goblin::elf::symver::VER_FLG_BASE;
 use goblin::elf::Elf;
-use goblin::mach::{self, Mach, MachO};
+use goblin::mach::constants::cputype::{get_arch_from_flag, get_arch_name_from_types};
+use goblin::mach::constants::{S_ATTR_PURE_INSTRUCTIONS, S_ATTR_SOME_INSTRUCTIONS};
+use goblin::mach::segment::Section;
+use goblin::mach::symbols::{Nlist, N_PEXT, N_SECT};
+use goblin::mach::{Mach, MachO, MultiArch, SingleArch};
+use goblin::pe::header::{
+    CoffHeader, COFF_MACHINE_ARM64, COFF_MACHINE_ARMNT, COFF_MACHINE_X86, COFF_MACHINE_X86_64,
+};
+use goblin::pe::section_table::{SectionTable, IMAGE_SCN_CNT_CODE, IMAGE_SCN_MEM_EXECUTE};
+use goblin::pe::symbol::IMAGE_SYM_CLASS_EXTERNAL;
+use goblin::pe::{Coff, PE};
+use goblin::strtab::Strtab;
 use goblin::Object;
 
 use crate::errors::*;
-use crate::patcher::BUILTIN_PREFIX;
+use crate::functions_ids::{function_import_type_ids, function_type_ids};
+use crate::patcher::exported_functions;
+use crate::sections::{Module, WASM_MAGIC};
+use crate::signatures::{function_signatures, Signature, Signatures};
+
+/// Where a builtin symbol was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolTable {
+    /// ELF dynamic symbol table (`.dynsym`)
+    ElfDynamic,
+    /// ELF static symbol table (`.symtab`)
+    ElfStatic,
+    /// Mach-O symbol table
+    MachO,
+    /// PE export table
+    PeExports,
+    /// COFF object symbol table
+    Coff,
+    /// Exports of a WebAssembly module
+    WasmExports,
+    /// Builtins manifest
+    Manifest,
+    /// Names given on the command line
+    Additional,
+}
+
+/// Slices of fat Mach-O builtins libraries to extract symbols from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Architecture {
+    /// Architecture of the host
+    #[default]
+    Host,
+    /// Architecture name, as used by `lipo` (`x86_64`, `arm64`...)
+    Named(String),
+    /// Every slice, which must all define the same builtins
+    All,
+}
+
+impl Architecture {
+    fn name(&self) -> Option<&str> {
+        match self {
+            Architecture::Host => Some(match std::env::consts::ARCH {
+                "aarch64" => "arm64",
+                "x86" => "i386",
+                "powerpc" => "ppc",
+                "powerpc64" => "ppc64",
+                arch => arch,
+            }),
+            Architecture::Named(name) => Some(name),
+            Architecture::All => None,
+        }
+    }
+}
 
 #[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Also scan the static symbol table (`.symtab`) of ELF files
+    pub include_static_symbols: bool,
+    /// Only keep ELF symbols with this version
+    pub required_version: Option<String>,
+    pub architecture: Architecture,
+}
+
+#[derive(Clone, Debug)]
 pub struct ExtractedSymbol {
     pub name: String,
+    pub table: SymbolTable,
+    /// Archive member defining the symbol
+    pub member: Option<String>,
+    /// Type of the function, for WebAssembly builtins libraries and manifests
+    pub signature: Option<Signature>,
+    /// Module to import the builtin from, overriding the configured one
+    pub import_module: Option<String>,
+    /// Name of the import, if different from the name of the symbol
+    pub import_name: Option<String>,
+    /// Version of a versioned ELF symbol
+    pub version: Option<String>,
+    /// Whether the symbol is the default version of a versioned symbol (`name@@version`)
+    pub default_version: bool,
+    /// Builtins library defining the symbol
+    pub library: Option<PathBuf>,
+    /// Libraries also defining the symbol, that were given a lower precedence
+    pub shadowed_libraries: Vec<PathBuf>,
+}
+
+impl ExtractedSymbol {
+    pub fn new(name: String, table: SymbolTable) -> Self {
+        ExtractedSymbol {
+            name,
+            table,
+            member: None,
+            signature: None,
+            import_module: None,
+            import_name: None,
+            version: None,
+            default_version: true,
+            library: None,
+            shadowed_libraries: vec![],
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -26,22 +137,71 @@ impl From<Vec<ExtractedSymbol>> for ExtractedSymbols {
 }
 
 impl ExtractedSymbols {
-    pub fn builtins_names(&self) -> Vec<&str> {
-        let builtins_names: Vec<&str> = self
-            .symbols
+    pub fn names(&self) -> Vec<&str> {
+        self.symbols
             .iter()
-            .filter(|symbol| symbol.name.starts_with(BUILTIN_PREFIX))
-            .map(|symbol| &symbol.name[BUILTIN_PREFIX.len()..])
-            .collect();
-        builtins_names
+            .map(|symbol| symbol.name.as_str())
+            .collect()
+    }
+
+    pub fn has_signatures(&self) -> bool {
+        self.symbols.iter().any(|symbol| symbol.signature.is_some())
+    }
+
+    pub fn signatures(&self) -> Signatures {
+        self.symbols
+            .iter()
+            .filter_map(|symbol| Some((symbol.name.clone(), symbol.signature.clone()?)))
+            .collect()
+    }
+
+    /// Adds the symbols of another library. Symbols that are already present take precedence.
+    pub fn merge_library(mut self, library: ExtractedSymbols) -> Self {
+        for library_symbol in library.symbols {
+            match self
+                .symbols
+                .iter_mut()
+                .find(|symbol| symbol.name == library_symbol.name)
+            {
+                None => self.symbols.push(library_symbol),
+                Some(symbol) => symbol.shadowed_libraries.extend(library_symbol.library),
+            }
+        }
+        self
+    }
+
+    /// Adds the symbols of a manifest. Entries describing a symbol that is already present
+    /// override its import module, import name and signature.
+    pub fn merge_manifest(mut self, manifest: ExtractedSymbols) -> Self {
+        for manifest_symbol in manifest.symbols {
+            let symbol = match self
+                .symbols
+                .iter_mut()
+                .find(|symbol| symbol.name == manifest_symbol.name)
+            {
+                None => {
+                    self.symbols.push(manifest_symbol);
+                    continue;
+                }
+                Some(symbol) => symbol,
+            };
+            if manifest_symbol.import_module.is_some() {
+                symbol.import_module = manifest_symbol.import_module;
+            }
+            if manifest_symbol.import_name.is_some() {
+                symbol.import_name = manifest_symbol.import_name;
+            }
+            if manifest_symbol.signature.is_some() {
+                symbol.signature = manifest_symbol.signature;
+            }
+        }
+        self
     }
 
     pub fn merge_additional(mut self, additional_names: &[String]) -> Self {
         let mut additional_symbols: Vec<_> = additional_names
             .iter()
-            .map(|name| ExtractedSymbol {
-                name: name.to_string(),
-            })
+            .map(|name| ExtractedSymbol::new(name.to_string(), SymbolTable::Additional))
             .collect();
         self.symbols.append(&mut additional_symbols);
         self.symbols.dedup_by(|a, b| a.name == b.name);
@@ -49,88 +209,416 @@ impl ExtractedSymbols {
     }
 }
 
-fn parse_elf(elf: &Elf<'_>) -> Result<ExtractedSymbols, WError> {
-    let mut symbols = vec![];
+/// Checks that an ELF symbol is a function defined by the library, that can be linked
+/// against from outside of it.
+fn is_elf_builtin(symbol: &Sym, table: SymbolTable) -> bool {
+    if symbol.st_shndx == SHN_UNDEF as usize {
+        return false;
+    }
+    if !matches!(symbol.st_type(), sym::STT_FUNC | sym::STT_GNU_IFUNC) {
+        return false;
+    }
+    if !matches!(
+        symbol.st_bind(),
+        sym::STB_GLOBAL | sym::STB_WEAK | sym::STB_GNU_UNIQUE
+    ) {
+        return false;
+    }
+    // Hidden symbols of an object file can still be linked statically, but they can't be
+    // resolved at runtime
+    table == SymbolTable::ElfStatic
+        || !matches!(symbol.st_visibility(), sym::STV_HIDDEN | sym::STV_INTERNAL)
+}
 
-    for symbol in elf
-        .dynsyms
+/// Returns the version of every dynamic symbol, and whether it is the default version of the
+/// symbol (`name@@version`) or not (`name@version`).
+fn elf_symbol_versions<'a>(elf: &Elf<'a>) -> Vec<Option<(&'a str, bool)>> {
+    let (versym, verdef) = match (&elf.versym, &elf.verdef) {
+        (Some(versym), Some(verdef)) => (versym, verdef),
+        _ => return vec![],
+    };
+    let mut version_names = HashMap::new();
+    for definition in verdef.iter() {
+        if definition.vd_flags & VER_FLG_BASE != 0 {
+            continue;
+        }
+        if let Some(name) = definition
+            .iter()
+            .next()
+            .and_then(|aux| elf.dynstrtab.get_at(aux.vda_name))
+        {
+            version_names.insert(definition.vd_ndx, name);
+        }
+    }
+    versym
         .iter()
-        .filter(|symbol| symbol.st_info == 0x12 || symbol.st_info == 0x22)
-    {
-        #[allow(deprecated)]
-        let name = elf
-            .dynstrtab
-            .get(symbol.st_name)
-            .ok_or(WError::ParseError)?
-            .map_err(|_| WError::ParseError)?
-            .to_string();
-        let extracted_symbol = ExtractedSymbol { name };
-        symbols.push(extracted_symbol);
+        .map(|versym| {
+            let name = version_names.get(&versym.version())?;
+            Some((*name, !versym.is_hidden()))
+        })
+        .collect()
+}
+
+fn parse_elf_symbols(
+    symbols: &mut Vec<ExtractedSymbol>,
+    syms: &Symtab<'_>,
+    strtab: &Strtab<'_>,
+    versions: &[Option<(&str, bool)>],
+    table: SymbolTable,
+    required_version: Option<&str>,
+) -> Result<(), WError> {
+    for (idx, symbol) in syms.iter().enumerate() {
+        if !is_elf_builtin(&symbol, table) {
+            continue;
+        }
+        let name = strtab.get_at(symbol.st_name).ok_or(WError::ParseError)?;
+        // Static symbol tables include the version in the name
+        let (name, version) = match name.split_once('@') {
+            None => (name, versions.get(idx).copied().flatten()),
+            Some((name, version)) => match version.strip_prefix('@') {
+                Some(version) => (name, Some((version, true))),
+                None => (name, Some((version, false))),
+            },
+        };
+        if required_version.is_some() && version.map(|(version, _)| version) != required_version {
+            continue;
+        }
+        let mut extracted_symbol = ExtractedSymbol::new(name.to_string(), table);
+        if let Some((version, default_version)) = version {
+            extracted_symbol.version = Some(version.to_string());
+            extracted_symbol.default_version = default_version;
+        }
+        // The default version of a symbol wins over the other ones
+        match symbols.iter().position(|symbol| symbol.name == name) {
+            None => symbols.push(extracted_symbol),
+            Some(idx) if extracted_symbol.default_version && !symbols[idx].default_version => {
+                symbols[idx] = extracted_symbol;
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse_elf(
+    elf: &Elf<'_>,
+    include_static_symbols: bool,
+    required_version: Option<&str>,
+) -> Result<ExtractedSymbols, WError> {
+    let mut symbols = vec![];
+    parse_elf_symbols(
+        &mut symbols,
+        &elf.dynsyms,
+        &elf.dynstrtab,
+        &elf_symbol_versions(elf),
+        SymbolTable::ElfDynamic,
+        required_version,
+    )?;
+    if include_static_symbols {
+        parse_elf_symbols(
+            &mut symbols,
+            &elf.syms,
+            &elf.strtab,
+            &[],
+            SymbolTable::ElfStatic,
+            required_version,
+        )?;
     }
     Ok(symbols.into())
 }
 
+/// Checks that a Mach-O symbol is an external function defined by the library.
+fn is_macho_builtin(nlist: &Nlist, sections: &[Section]) -> bool {
+    if nlist.is_stab() || !nlist.is_global() || nlist.n_type & N_PEXT != 0 {
+        return false;
+    }
+    if nlist.get_type() != N_SECT {
+        return false;
+    }
+    // Sections are numbered from 1, across all segments
+    let section = match nlist
+        .n_sect
+        .checked_sub(1)
+        .and_then(|idx| sections.get(idx))
+    {
+        None => return false,
+        Some(section) => section,
+    };
+    if section.flags & (S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS) == 0 {
+        return false;
+    }
+    nlist.n_value >= section.addr && nlist.n_value < section.addr + section.size
+}
+
 fn parse_macho(macho: &MachO<'_>) -> Result<ExtractedSymbols, WError> {
+    let mut sections = vec![];
+    for segment in macho.segments.iter() {
+        for (section, _) in segment.sections().map_err(|_| WError::ParseError)? {
+            sections.push(section);
+        }
+    }
+
     let mut symbols = vec![];
+    for symbol in macho.symbols.as_ref().ok_or(WError::ParseError)?.iter() {
+        let (name, nlist) = symbol.map_err(|_| WError::ParseError)?;
+        if !is_macho_builtin(&nlist, &sections) {
+            continue;
+        }
+        let name = match name.strip_prefix('_') {
+            Some(name) if !name.is_empty() => name,
+            _ => continue,
+        };
+        symbols.push(ExtractedSymbol::new(name.to_string(), SymbolTable::MachO));
+    }
+    Ok(symbols.into())
+}
 
-    // Start by finding the boundaries of the text section
-    let mut text_offset = None;
-    let mut text_size = None;
-    for section in macho.segments.sections() {
-        for segment in section {
-            if let Ok((
-                mach::segment::Section {
-                    sectname: [b'_', b'_', b't', b'e', b'x', b't', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-                    segname: [b'_', b'_', b'T', b'E', b'X', b'T', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-                    size,
-                    offset,
-                    ..
-                },
-                _,
-            )) = segment
-            {
-                text_offset = Some(offset as usize);
-                text_size = Some(size as usize);
+fn is_code_section(section: &SectionTable) -> bool {
+    section.characteristics & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE) != 0
+}
+
+/// Extracts the functions exported by a DLL. Forwarded and data exports are ignored.
+fn parse_pe(pe: &PE<'_>) -> Result<ExtractedSymbols, WError> {
+    let mut symbols = vec![];
+    for export in &pe.exports {
+        let name = match (export.name, &export.reexport) {
+            (Some(name), None) => name,
+            _ => continue,
+        };
+        let rva = export.rva as u32;
+        if !pe.sections.iter().any(|section| {
+            is_code_section(section)
+                && rva >= section.virtual_address
+                && rva < section.virtual_address + section.virtual_size
+        }) {
+            continue;
+        }
+        symbols.push(ExtractedSymbol::new(
+            name.to_string(),
+            SymbolTable::PeExports,
+        ));
+    }
+    Ok(symbols.into())
+}
+
+/// Parses a COFF object. These have no magic number, so the machine and the header are
+/// checked first, to avoid reading any other file as a COFF object.
+fn coff_object(buffer: &[u8]) -> Option<Coff<'_>> {
+    let header = CoffHeader::parse(buffer, &mut 0).ok()?;
+    match header.machine {
+        COFF_MACHINE_X86 | COFF_MACHINE_X86_64 | COFF_MACHINE_ARMNT | COFF_MACHINE_ARM64 => {}
+        _ => return None,
+    }
+    // Only images have an optional header
+    if header.size_of_optional_header != 0
+        || header.number_of_sections == 0
+        || header.pointer_to_symbol_table as usize >= buffer.len()
+    {
+        return None;
+    }
+    Coff::parse(buffer).ok()
+}
+
+/// Extracts the external functions defined by a COFF object.
+fn parse_coff(coff: &Coff<'_>) -> Result<ExtractedSymbols, WError> {
+    let mut symbols = vec![];
+    for (_, _, symbol) in coff.symbols.iter() {
+        if symbol.storage_class != IMAGE_SYM_CLASS_EXTERNAL || symbol.section_number <= 0 {
+            continue;
+        }
+        // Sections are numbered from 1
+        match coff.sections.get(symbol.section_number as usize - 1) {
+            Some(section) if is_code_section(section) => {}
+            _ => continue,
+        }
+        let name = symbol.name(&coff.strings).map_err(|_| WError::ParseError)?;
+        // 32-bit x86 C symbols are decorated with a leading underscore
+        let name = match coff.header.machine {
+            COFF_MACHINE_X86 => match name.strip_prefix('_') {
+                Some(name) => name,
+                None => continue,
+            },
+            _ => name,
+        };
+        symbols.push(ExtractedSymbol::new(name.to_string(), SymbolTable::Coff));
+    }
+    Ok(symbols.into())
+}
+
+/// Extracts the functions exported by a WebAssembly module, along with their types.
+fn parse_wasm(buffer: &[u8]) -> Result<ExtractedSymbols, WError> {
+    let module = Module::from_bytes(buffer)?;
+    let mut type_ids = function_import_type_ids(&module)?;
+    type_ids.extend(function_type_ids(&module)?);
+    let signatures = function_signatures(&module)?;
+    let mut symbols = vec![];
+    for (function_id, name) in exported_functions(&module)? {
+        let signature = type_ids
+            .get(function_id as usize)
+            .and_then(|type_id| signatures.get(*type_id as usize))
+            .cloned()
+            .flatten();
+        let mut symbol = ExtractedSymbol::new(name, SymbolTable::WasmExports);
+        symbol.signature = signature;
+        symbols.push(symbol);
+    }
+    Ok(symbols.into())
+}
+
+/// Scans every object of a static archive. Like a linker would, the first member defining a
+/// symbol wins.
+fn parse_archive(
+    archive: &Archive<'_>,
+    buffer: &[u8],
+    required_version: Option<&str>,
+) -> Result<ExtractedSymbols, Error> {
+    let mut symbols: Vec<ExtractedSymbol> = vec![];
+    let mut has_objects = false;
+    for member in archive.members() {
+        let member_buffer = archive
+            .extract(member, buffer)
+            .map_err(|_| WError::ParseError)?;
+        // Object files only have a static symbol table
+        let member_symbols = match Object::parse(member_buffer).map_err(|_| WError::ParseError)? {
+            Object::Mach(Mach::Binary(macho)) => parse_macho(&macho)?,
+            Object::Elf(elf) => parse_elf(&elf, true, required_version)?,
+            Object::Unknown(_) => match coff_object(member_buffer) {
+                Some(coff) => parse_coff(&coff)?,
+                None => continue,
+            },
+            _ => continue,
+        };
+        has_objects = true;
+        for mut symbol in member_symbols.symbols {
+            if symbols.iter().any(|other| other.name == symbol.name) {
+                continue;
             }
+            symbol.member = Some(member.to_string());
+            symbols.push(symbol);
         }
     }
-    let text_offset = text_offset.ok_or(WError::ParseError)?;
-    let text_size = text_size.ok_or(WError::ParseError)?;
+    if !has_objects && !archive.members().is_empty() {
+        bail!(WError::Unsupported);
+    }
+    Ok(symbols.into())
+}
 
-    // Extract the symbols we are interested in
-    for symbol in macho.symbols.as_ref().ok_or(WError::ParseError)?.iter() {
-        match symbol {
-            Ok((
+fn parse_fat_slice(
+    multi_arch: &MultiArch<'_>,
+    idx: usize,
+    buffer: &[u8],
+) -> Result<ExtractedSymbols, Error> {
+    let symbols = match multi_arch.get(idx).map_err(|_| WError::ParseError)? {
+        SingleArch::MachO(macho) => parse_macho(&macho)?,
+        SingleArch::Archive(archive) => {
+            let arch = multi_arch
+                .iter_arches()
+                .nth(idx)
+                .ok_or(WError::ParseError)?
+                .map_err(|_| WError::ParseError)?;
+            parse_archive(&archive, arch.slice(buffer), None)?
+        }
+    };
+    Ok(symbols)
+}
+
+/// Extracts the symbols of the selected slice of a fat binary, or of all of them if they
+/// agree on the set of symbols they define.
+fn parse_fat(
+    multi_arch: &MultiArch<'_>,
+    buffer: &[u8],
+    architecture: &Architecture,
+) -> Result<ExtractedSymbols, Error> {
+    let arches = multi_arch.arches().map_err(|_| WError::ParseError)?;
+    let arch_names: Vec<String> = arches
+        .iter()
+        .map(
+            |arch| match get_arch_name_from_types(arch.cputype(), arch.cpusubtype()) {
+                Some(name) => name.to_string(),
+                None => format!("cputype {}", arch.cputype()),
+            },
+        )
+        .collect();
+
+    if let Some(name) = architecture.name() {
+        let (cputype, _) =
+            get_arch_from_flag(name).ok_or(WError::UsageError("Unknown architecture"))?;
+        let idx = match arches.iter().position(|arch| arch.cputype() == cputype) {
+            Some(idx) => idx,
+            None => bail!(WError::ArchitectureNotFound(format!(
+                "{} (available: {})",
                 name,
-                mach::symbols::Nlist {
-                    n_type: 0xf,
-                    n_sect: 1,
-                    n_value,
-                    ..
-                },
-            )) if name.len() > 1 && name.starts_with('_') => {
-                let extracted_symbol = ExtractedSymbol {
-                    name: name[1..].to_string(),
-                };
-                let offset = n_value as usize;
-                if offset < text_offset || offset >= text_offset + text_size {
-                    continue;
-                }
-                symbols.push(extracted_symbol);
+                arch_names.join(", ")
+            ))),
+        };
+        return parse_fat_slice(multi_arch, idx, buffer);
+    }
+
+    let mut all_symbols: Option<(&str, ExtractedSymbols)> = None;
+    for (idx, arch_name) in arch_names.iter().enumerate() {
+        let symbols = parse_fat_slice(multi_arch, idx, buffer)?;
+        let (first_arch_name, first_symbols) = match &all_symbols {
+            None => {
+                all_symbols = Some((arch_name, symbols));
+                continue;
             }
-            _ => {}
+            Some(first) => first,
+        };
+        let (mut first_names, mut names) = (first_symbols.names(), symbols.names());
+        first_names.sort_unstable();
+        names.sort_unstable();
+        if first_names != names {
+            let only_first: Vec<_> = first_names
+                .iter()
+                .filter(|name| !names.contains(name))
+                .collect();
+            let only_other: Vec<_> = names
+                .iter()
+                .filter(|name| !first_names.contains(name))
+                .collect();
+            bail!(WError::InconsistentArchitectures(format!(
+                "{} and {} define different symbols (only in {}: {:?}, only in {}: {:?})",
+                first_arch_name, arch_name, first_arch_name, only_first, arch_name, only_other
+            )));
         }
     }
-    Ok(symbols.into())
+    Ok(all_symbols.map(|(_, symbols)| symbols).unwrap_or_default())
 }
 
-pub fn extract_symbols<P: AsRef<Path>>(path: P) -> Result<ExtractedSymbols, Error> {
+/// Extracts the functions defined by a builtins library.
+///
+/// Only the dynamic symbol table of ELF files is used, unless `include_static_symbols` is
+/// set, in which case `.symtab` is also scanned. This is required for object files, and
+/// always done for the members of static archives. Fat Mach-O binaries are handled according
+/// to `architecture`.
+pub fn extract_symbols<P: AsRef<Path>>(
+    path: P,
+    options: &ExtractOptions,
+) -> Result<ExtractedSymbols, Error> {
     let mut buffer = Vec::new();
     File::open(path)?.read_to_end(&mut buffer)?;
+    if buffer.starts_with(WASM_MAGIC) {
+        return Ok(parse_wasm(&buffer)?);
+    }
     let symbols = match Object::parse(&buffer).map_err(|_| WError::ParseError)? {
-        Object::Mach(Mach::Binary(macho)) => parse_macho(&macho),
-        Object::Elf(elf) => parse_elf(&elf),
-        _ => bail!(WError::Unsupported),
-    }?;
+        Object::Mach(Mach::Binary(macho)) => parse_macho(&macho)?,
+        Object::Mach(Mach::Fat(multi_arch)) => {
+            parse_fat(&multi_arch, &buffer, &options.architecture)?
+        }
+        Object::Elf(elf) => parse_elf(
+            &elf,
+            options.include_static_symbols,
+            options.required_version.as_deref(),
+        )?,
+        Object::Archive(archive) => {
+            parse_archive(&archive, &buffer, options.required_version.as_deref())?
+        }
+        Object::PE(pe) => parse_pe(&pe)?,
+        // COFF objects have no magic number
+        Object::Unknown(_) => match coff_object(&buffer) {
+            Some(coff) => parse_coff(&coff)?,
+            None => bail!(WError::Unsupported),
+        },
+    };
     Ok(symbols)
 }
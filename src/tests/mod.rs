@@ -1,7 +1,13 @@
-use std::hash::Hasher;
+use std::collections::HashMap;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::{env, process};
 
-use siphasher::sip::SipHasher13;
+use wasm_encoder::SectionId;
+use wasmparser::{
+    BinaryReader, CodeSectionReader, ElementItems, ElementSectionReader, GlobalSectionReader,
+    ImportSectionReader, Name, NameSectionReader, Operator, Validator, WasmFeatures,
+};
 
 use super::*;
 
@@ -9,40 +15,1205 @@ lazy_static! {
     static ref TESTS_DIR: PathBuf = Path::new(file!()).parent().unwrap().canonicalize().unwrap();
 }
 
+fn start_function_id(module: &Module) -> u32 {
+    let start_section = module.section(SectionId::Start).unwrap();
+    start_section.reader().read_var_u32().unwrap()
+}
+
+fn function_body_operators(module: &Module, body_idx: usize) -> Vec<Operator<'_>> {
+    let code_section = module.section(SectionId::Code).unwrap();
+    let body = CodeSectionReader::new(code_section.reader())
+        .unwrap()
+        .into_iter()
+        .nth(body_idx)
+        .unwrap()
+        .unwrap();
+    let mut operators_reader = body.get_operators_reader().unwrap();
+    let mut operators = vec![];
+    while !operators_reader.eof() {
+        operators.push(operators_reader.read().unwrap());
+    }
+    operators
+}
+
+/// Returns the functions called by a function body, in order.
+fn called_functions(module: &Module, body_idx: usize) -> Vec<u32> {
+    function_body_operators(module, body_idx)
+        .into_iter()
+        .filter_map(|operator| match operator {
+            Operator::Call { function_index } => Some(function_index),
+            _ => None,
+        })
+        .collect()
+}
+
+fn imported_functions(module: &Module) -> Vec<String> {
+    crate::functions_ids::function_imports(module)
+        .unwrap()
+        .into_iter()
+        .map(|(module, name, _)| format!("{}.{}", module, name))
+        .collect()
+}
+
+fn validate(bytes: &[u8]) {
+    Validator::new_with_features(WasmFeatures::all())
+        .validate_all(bytes)
+        .unwrap();
+}
+
 #[test]
 fn patch_nothing() {
     let path_in = TESTS_DIR.join("test_1.wasm");
     let config = PatcherConfig::default();
-    let patcher = Patcher::from_file(config, path_in).unwrap();
-    let mut hasher = SipHasher13::new();
-    hasher.write(&patcher.into_bytes().unwrap());
-    assert_eq!(hasher.finish(), 1401932366200566186);
+    let patcher = Patcher::from_file(config, &path_in).unwrap();
+    assert_eq!(patcher.into_bytes().unwrap(), fs::read(&path_in).unwrap());
+
+    // No import section is added to a module without types
+    let bytes = wat::parse_str("(module (memory 1))").unwrap();
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_bytes(config, &bytes).unwrap();
+    assert_eq!(patcher.into_bytes().unwrap(), bytes);
 }
 
 #[test]
 fn patch_one() {
     let path_in = TESTS_DIR.join("test_1.wasm");
-    let mut config = PatcherConfig::default();
-    config.builtins_additional = ["builtin_memmove", "builtin_nonexistent", "not_a_builtin"]
+    let config = PatcherConfig {
+        builtins_additional: ["builtin_memmove", "builtin_nonexistent", "not_a_builtin"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+    let module = patcher.patched_module();
+    validate(&module.to_bytes());
+    assert_eq!(imported_functions(&module), ["env.builtin_memmove"]);
+
+    // `entrypoint` calls `strcmp`, now shifted, and the import instead of `memmove`
+    assert_eq!(called_functions(&module, 0), [5, 0]);
+    // `memmove` is still there and calls `memcpy`
+    assert_eq!(called_functions(&module, 3), [6]);
+    let exports: HashMap<_, _> = exported_functions(&module)
+        .unwrap()
+        .into_iter()
+        .map(|(function_id, name)| (name, function_id))
+        .collect();
+    assert_eq!(exports["memmove"], 4);
+    assert_eq!(exports["memcpy"], 6);
+}
+
+#[test]
+fn patch_some() {
+    let path_in = TESTS_DIR.join("test_1.wasm");
+    let config = PatcherConfig {
+        builtins_additional: ["builtin_memmove", "builtin_memcpy", "builtin_strcmp"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+    let module = patcher.patched_module();
+    validate(&module.to_bytes());
+    let imports = imported_functions(&module);
+    let mut sorted_imports = imports.clone();
+    sorted_imports.sort_unstable();
+    assert_eq!(
+        sorted_imports,
+        [
+            "env.builtin_memcpy",
+            "env.builtin_memmove",
+            "env.builtin_strcmp"
+        ]
+    );
+    let import = |name: &str| imports.iter().position(|import| import == name).unwrap() as u32;
+
+    // Every call to a replaced function now targets its import
+    assert_eq!(
+        called_functions(&module, 0),
+        [import("env.builtin_strcmp"), import("env.builtin_memmove")]
+    );
+    assert_eq!(called_functions(&module, 3), [import("env.builtin_memcpy")]);
+}
+
+#[test]
+fn patch_with_start_section() {
+    let path_in = TESTS_DIR.join("test_2.wasm");
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+    let module = patcher.patched_module();
+    assert_eq!(start_function_id(&module), 2);
+}
+
+#[test]
+fn patch_start_function() {
+    let path_in = TESTS_DIR.join("test_2.wasm");
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_init".to_string()],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+    let module = patcher.patched_module();
+    assert_eq!(start_function_id(&module), 0);
+}
+
+#[test]
+fn patch_index_keyed_names() {
+    let path_in = TESTS_DIR.join("test_3.wasm");
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+    let module = patcher.patched_module();
+
+    let names_payload = &module.custom_section("name").unwrap().payload;
+    let contains = |needle: &[u8]| names_payload.windows(needle.len()).any(|w| w == needle);
+    // Label names of `run`, now function 2
+    assert!(contains(b"\x03\x09\x01\x02\x01\x00\x04done"));
+    // Global names are kept as-is
+    assert!(contains(b"\x07\x0a\x01\x00\x07counter"));
+
+    let mut function_names = vec![];
+    let mut local_names = vec![];
+    for name in NameSectionReader::new(BinaryReader::new(names_payload, 0)) {
+        match name.unwrap() {
+            Name::Function(names) => {
+                for naming in names {
+                    let naming = naming.unwrap();
+                    function_names.push((naming.index, naming.name));
+                }
+            }
+            Name::Local(names) => {
+                for indirect_naming in names {
+                    let indirect_naming = indirect_naming.unwrap();
+                    for naming in indirect_naming.names {
+                        let naming = naming.unwrap();
+                        local_names.push((indirect_naming.index, naming.index, naming.name));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(
+        function_names,
+        [(0, "builtin_memcpy"), (1, "memcpy"), (2, "run")]
+    );
+    assert!(local_names.contains(&(1, 0, "dst")));
+    assert!(local_names.contains(&(2, 1, "ret")));
+}
+
+#[test]
+fn patch_relocatable_object() {
+    let path_in = TESTS_DIR.join("test_4.wasm");
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+    let bytes = patcher.into_bytes().unwrap();
+
+    // The call to `memcpy` now targets the import, the call to `abort` was shifted,
+    // and both operands are still padded
+    assert!(bytes
+        .windows(12)
+        .any(|w| w == b"\x10\x80\x80\x80\x80\x00\x10\x81\x80\x80\x80\x00"));
+    let module = Module::from_bytes(&bytes).unwrap();
+    let run_code = function_body_operators(&module, 1);
+    assert_eq!(run_code[3], Operator::Call { function_index: 0 });
+    assert_eq!(run_code[4], Operator::Call { function_index: 1 });
+
+    let custom_section_payload = |name: &str| module.custom_section(name).unwrap().payload.clone();
+
+    // Function symbols were shifted, and an undefined symbol was added for the import
+    let linking_payload = custom_section_payload("linking");
+    assert_eq!(
+        linking_payload,
+        b"\x02\x08\x22\x05\
+          \x00\x00\x02\x06memcpy\
+          \x00\x10\x01\
+          \x00\x00\x03\x03run\
+          \x01\x00\x04fptr\x00\x00\x04\
+          \x00\x10\x00"
+    );
+
+    // The call and the function pointer to `memcpy` now use the new symbol
+    let reloc_code_payload = custom_section_payload("reloc.CODE");
+    assert_eq!(reloc_code_payload, b"\x04\x02\x00\x0f\x04\x00\x15\x01");
+    let reloc_data_payload = custom_section_payload("reloc.DATA");
+    assert_eq!(reloc_data_payload, b"\x05\x01\x02\x06\x04");
+}
+
+#[test]
+fn patch_relocatable_object_twice() {
+    let path_in = TESTS_DIR.join("test_4.wasm");
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        ..Default::default()
+    };
+    let patched = Patcher::from_file(config.clone(), path_in)
+        .unwrap()
+        .into_bytes()
+        .unwrap();
+
+    // Objects have no provenance section, but the builtin import is found and reused,
+    // without adding another undefined symbol
+    let module = Patcher::from_bytes(config, &patched)
+        .unwrap()
+        .patched_module();
+    assert_eq!(
+        imported_functions(&module),
+        ["env.builtin_memcpy", "env.abort"]
+    );
+    assert_eq!(module.to_bytes(), patched);
+}
+
+#[test]
+fn patch_reference_types() {
+    let path_in = TESTS_DIR.join("test_5.wasm");
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+    let module = patcher.patched_module();
+    let ref_func = |function_index| Operator::RefFunc { function_index };
+
+    // Function references in globals, table entries and code are redirected
+    let global_section = module.section(SectionId::Global).unwrap();
+    let global = GlobalSectionReader::new(global_section.reader())
+        .unwrap()
+        .into_iter()
+        .next()
+        .unwrap()
+        .unwrap();
+    let mut init_expr = global.init_expr.get_operators_reader();
+    assert_eq!(init_expr.read().unwrap(), ref_func(0));
+
+    let element_section = module.section(SectionId::Element).unwrap();
+    let element = ElementSectionReader::new(element_section.reader())
+        .unwrap()
+        .into_iter()
+        .next()
+        .unwrap()
+        .unwrap();
+    let items: Vec<_> = match element.items {
+        ElementItems::Expressions(_, exprs) => exprs
+            .into_iter()
+            .map(|expr| expr.unwrap().get_operators_reader().read().unwrap())
+            .collect(),
+        ElementItems::Functions(_) => unreachable!(),
+    };
+    assert_eq!(items, [ref_func(0), ref_func(2)]);
+
+    assert!(function_body_operators(&module, 1).contains(&ref_func(0)));
+}
+
+#[test]
+fn patch_tail_calls() {
+    let bytes = wat::parse_file(TESTS_DIR.join("test_6.wat")).unwrap();
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_bytes(config, &bytes).unwrap();
+    let module = patcher.patched_module();
+
+    let copy_code = function_body_operators(&module, 1);
+    assert!(copy_code.contains(&Operator::ReturnCall { function_index: 0 }));
+    let copy_ref_code = function_body_operators(&module, 2);
+    assert!(copy_ref_code.contains(&Operator::RefFunc { function_index: 0 }));
+}
+
+#[test]
+fn patch_import_modules() {
+    let path_in = TESTS_DIR.join("test_1.wasm");
+    let config = PatcherConfig {
+        builtins_additional: ["builtin_memmove", "builtin_memcpy", "builtin_strcmp"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        builtins_import_module: Some("libc".to_string()),
+        builtins_import_modules: vec![("memcpy".to_string(), "crypto".to_string())]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+
+    let libc_map = patcher.patched_builtins_map("libc").unwrap();
+    assert_eq!(libc_map.len(), 2);
+    assert!(libc_map.contains_key("builtin_memmove"));
+    assert!(libc_map.contains_key("builtin_strcmp"));
+    let crypto_map = patcher.patched_builtins_map("crypto").unwrap();
+    assert_eq!(crypto_map.len(), 1);
+    assert!(crypto_map.contains_key("builtin_memcpy"));
+    assert!(patcher.patched_builtins_map("env").unwrap().is_empty());
+
+    let module = patcher.patched_module();
+    let import_section = module.section(SectionId::Import).unwrap();
+    let imports: Vec<_> = ImportSectionReader::new(import_section.reader())
+        .unwrap()
+        .into_imports()
+        .take(3)
+        .map(|import| {
+            let import = import.unwrap();
+            (import.module, import.name)
+        })
+        .collect();
+    assert_eq!(
+        imports,
+        [
+            ("libc", "builtin_strcmp"),
+            ("crypto", "builtin_memcpy"),
+            ("libc", "builtin_memmove")
+        ]
+    );
+}
+
+#[test]
+fn patch_renamed_builtins() {
+    let path_in = TESTS_DIR.join("test_1.wasm");
+    let mut builtins_rename_rules = RenameRules {
+        prefix: Some("__wasm_host_".to_string()),
+        pairs: vec![("strcmp".to_string(), "host_strcmp".to_string())]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    };
+    builtins_rename_rules
+        .add_substitution("^mem(.*)$", "mem_$1")
+        .unwrap();
+    let config = PatcherConfig {
+        builtins_additional: [
+            "builtin_memmove",
+            "__wasm_host_mem_move",
+            "host_strcmp",
+            "__wasm_host_strcmp",
+        ]
         .iter()
         .map(|s| s.to_string())
+        .collect(),
+        builtins_rename_rules,
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+    let mut builtins_map: Vec<_> = patcher
+        .patched_builtins_map("env")
+        .unwrap()
+        .into_keys()
         .collect();
+    builtins_map.sort();
+    assert_eq!(builtins_map, ["__wasm_host_mem_move", "host_strcmp"]);
+
+    let config = PatcherConfig {
+        builtins_additional: vec!["memcpy".to_string()],
+        builtins_rename_rules: RenameRules {
+            prefix: Some("".to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, TESTS_DIR.join("test_1.wasm"));
+    let builtins_map = patcher.unwrap().patched_builtins_map("env").unwrap();
+    assert!(builtins_map.contains_key("memcpy"));
+}
+
+#[test]
+fn patch_named_functions() {
+    let path_in = TESTS_DIR.join("test_7.wasm");
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_copy_impl".to_string()],
+        ..Default::default()
+    };
     let patcher = Patcher::from_file(config, path_in).unwrap();
-    let mut hasher = SipHasher13::new();
-    hasher.write(&patcher.into_bytes().unwrap());
-    assert_eq!(hasher.finish(), 12884721342785729260);
+    let module = patcher.patched_module();
+    let run_code = function_body_operators(&module, 1);
+    assert!(run_code.contains(&Operator::Call { function_index: 0 }));
 }
 
 #[test]
-fn patch_some() {
+fn patch_function_with_several_names() {
+    let bytes = wat::parse_str(
+        r#"(module
+          (func $copy (export "memcpy") (export "memmove") (param i32 i32 i32) (result i32)
+            local.get 0)
+          (func (export "run") (result i32)
+            i32.const 0
+            i32.const 0
+            i32.const 0
+            call $copy))"#,
+    )
+    .unwrap();
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string(), "builtin_memmove".to_string()],
+        ..Default::default()
+    };
+
+    // Only the first builtin replaces the function
+    let patcher = Patcher::from_bytes(config, &bytes).unwrap();
+    assert_eq!(patcher.patched_builtins_map("env").unwrap().len(), 1);
+    let module = patcher.patched_module();
+    validate(&module.to_bytes());
+    assert_eq!(imported_functions(&module).len(), 1);
+    assert_eq!(called_functions(&module, 1), [0]);
+}
+
+#[test]
+fn patch_debug_info_functions() {
+    let path_in = TESTS_DIR.join("test_7.wasm");
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config.clone(), &path_in).unwrap();
+    assert!(patcher.patched_builtins_map("env").unwrap().is_empty());
+
+    let config = PatcherConfig {
+        builtins_match_debug_info: true,
+        ..config
+    };
+    let patcher = Patcher::from_file(config, &path_in).unwrap();
+    let builtins_map = patcher.patched_builtins_map("env").unwrap();
+    assert!(builtins_map.contains_key("builtin_memcpy"));
+    let module = patcher.patched_module();
+    let run_code = function_body_operators(&module, 1);
+    assert!(run_code.contains(&Operator::Call { function_index: 0 }));
+}
+
+#[test]
+fn patch_without_exports() {
+    let bytes = wat::parse_file(TESTS_DIR.join("test_8.wat")).unwrap();
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_bytes(config, &bytes).unwrap();
+    let module = patcher.patched_module();
+    assert!(module.section(SectionId::Export).is_none());
+    let run_code = function_body_operators(&module, 1);
+    assert!(run_code.contains(&Operator::Call { function_index: 0 }));
+}
+
+#[test]
+fn check_signatures() {
     let path_in = TESTS_DIR.join("test_1.wasm");
-    let mut config = PatcherConfig::default();
-    config.builtins_additional = ["builtin_memmove", "builtin_memcpy", "builtin_strcmp"]
+    let config = PatcherConfig {
+        builtins_libraries: vec![BuiltinsLibrary::new(TESTS_DIR.join("test_9.elf"))],
+        builtins_signature_check: SignatureCheck::Fail,
+        ..Default::default()
+    };
+
+    // `builtin_strcmp` has an extra parameter, according to the debugging information
+    let err = Patcher::from_file(config.clone(), &path_in).err().unwrap();
+    match err.downcast_ref::<WError>() {
+        Some(WError::IncompatibleSignature(message)) => assert!(message.contains("builtin_strcmp")),
+        _ => panic!("Unexpected error: {}", err),
+    }
+
+    // ...and according to the header
+    let header_config = PatcherConfig {
+        builtins_signatures_path: Some(TESTS_DIR.join("test_9.h")),
+        builtins_signature_check: SignatureCheck::Warn,
+        ..config.clone()
+    };
+    let patcher = Patcher::from_file(header_config, &path_in).unwrap();
+    assert_eq!(patcher.warnings().len(), 1);
+    assert_eq!(patcher.patched_builtins_map("env").unwrap().len(), 2);
+
+    // The manifest matches the types of the functions
+    let manifest_config = PatcherConfig {
+        builtins_signatures_path: Some(TESTS_DIR.join("test_9.json")),
+        ..config.clone()
+    };
+    let patcher = Patcher::from_file(manifest_config, &path_in).unwrap();
+    assert!(patcher.warnings().is_empty());
+
+    // Builtins missing from the builtins manifest are checked against the debugging information
+    let builtins_manifest_config = PatcherConfig {
+        builtins_manifest_path: Some(TESTS_DIR.join("test_23.json")),
+        ..config.clone()
+    };
+    let err = Patcher::from_file(builtins_manifest_config, &path_in)
+        .err()
+        .unwrap();
+    match err.downcast_ref::<WError>() {
+        Some(WError::IncompatibleSignature(message)) => assert!(message.contains("builtin_strcmp")),
+        _ => panic!("Unexpected error: {}", err),
+    }
+
+    // Archive members and fat slices are read, other libraries are skipped with a warning
+    let library_config = |library: &str, builtins_signature_check| PatcherConfig {
+        builtins_libraries: vec![BuiltinsLibrary::new(TESTS_DIR.join(library))],
+        builtins_signature_check,
+        builtins_architecture: Architecture::Named("x86_64".to_string()),
+        ..Default::default()
+    };
+    for library in &["test_11.a", "test_12.dylib"] {
+        let patcher =
+            Patcher::from_file(library_config(library, SignatureCheck::Warn), &path_in).unwrap();
+        assert!(patcher.warnings().is_empty());
+    }
+    let patcher = Patcher::from_file(
+        library_config("test_15.dll", SignatureCheck::Warn),
+        &path_in,
+    )
+    .unwrap();
+    assert_eq!(patcher.warnings().len(), 1);
+    assert!(patcher.warnings()[0].contains("test_15.dll"));
+    let err = Patcher::from_file(
+        library_config("test_15.dll", SignatureCheck::Fail),
+        &path_in,
+    )
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("test_15.dll"));
+}
+
+#[test]
+fn extract_elf_symbols() {
+    let names_and_tables = |symbols: &ExtractedSymbols| -> Vec<(String, SymbolTable)> {
+        symbols
+            .symbols
+            .iter()
+            .map(|symbol| (symbol.name.clone(), symbol.table))
+            .collect()
+    };
+
+    // Indirect and weak functions are exported, hidden ones are not
+    let symbols = extract_symbols(TESTS_DIR.join("test_10.elf"), &Default::default()).unwrap();
+    let mut names = symbols.names();
+    names.sort_unstable();
+    assert_eq!(
+        names,
+        ["builtin_memcpy", "builtin_memmove", "not_a_builtin_caller"]
+    );
+    assert!(symbols
+        .symbols
         .iter()
-        .map(|s| s.to_string())
+        .all(|symbol| symbol.table == SymbolTable::ElfDynamic));
+
+    // Object files only have a static symbol table
+    let static_options = ExtractOptions {
+        include_static_symbols: true,
+        ..Default::default()
+    };
+    let symbols = extract_symbols(TESTS_DIR.join("test_10.o"), &Default::default()).unwrap();
+    assert!(symbols.symbols.is_empty());
+    let symbols = extract_symbols(TESTS_DIR.join("test_10.o"), &static_options).unwrap();
+    let mut symbols = names_and_tables(&symbols);
+    symbols.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        symbols,
+        [
+            ("builtin_memcpy".to_string(), SymbolTable::ElfStatic),
+            ("builtin_memmove".to_string(), SymbolTable::ElfStatic),
+            ("builtin_strcmp".to_string(), SymbolTable::ElfStatic),
+            ("not_a_builtin_caller".to_string(), SymbolTable::ElfStatic),
+        ]
+    );
+}
+
+#[test]
+fn extract_archive_symbols() {
+    let symbols = extract_symbols(TESTS_DIR.join("test_11.a"), &Default::default()).unwrap();
+    let symbols: Vec<_> = symbols
+        .symbols
+        .iter()
+        .map(|symbol| (symbol.name.as_str(), symbol.member.as_deref()))
         .collect();
+    assert_eq!(
+        symbols,
+        [
+            ("builtin_memcpy", Some("memory.o")),
+            ("builtin_memmove", Some("memory.o")),
+            ("builtin_strcmp", Some("string.o")),
+            ("builtin_strlen", Some("string.o")),
+        ]
+    );
+}
+
+#[test]
+fn extract_fat_symbols() {
+    let extract = |file: &str, architecture: Architecture| {
+        let options = ExtractOptions {
+            architecture,
+            ..Default::default()
+        };
+        extract_symbols(TESTS_DIR.join(file), &options)
+    };
+
+    for arch in &["x86_64", "arm64"] {
+        let symbols = extract("test_12.dylib", Architecture::Named(arch.to_string())).unwrap();
+        assert_eq!(symbols.names(), ["builtin_memcpy", "builtin_memmove"]);
+    }
+    let symbols = extract("test_12.dylib", Architecture::All).unwrap();
+    assert_eq!(symbols.names(), ["builtin_memcpy", "builtin_memmove"]);
+    assert!(extract("test_12.dylib", Architecture::Named("ppc".to_string())).is_err());
+
+    // The arm64 slice doesn't define `builtin_memmove`
+    let symbols = extract(
+        "test_12_mismatch.dylib",
+        Architecture::Named("x86_64".to_string()),
+    );
+    assert_eq!(
+        symbols.unwrap().names(),
+        ["builtin_memcpy", "builtin_memmove"]
+    );
+    let symbols = extract(
+        "test_12_mismatch.dylib",
+        Architecture::Named("arm64".to_string()),
+    );
+    assert_eq!(symbols.unwrap().names(), ["builtin_memcpy"]);
+    let err = extract("test_12_mismatch.dylib", Architecture::All).unwrap_err();
+    assert!(err.to_string().contains("builtin_memmove"));
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[test]
+fn extract_fat_symbols_for_host() {
+    let symbols = extract_symbols(TESTS_DIR.join("test_12.dylib"), &Default::default()).unwrap();
+    assert_eq!(symbols.names(), ["builtin_memcpy", "builtin_memmove"]);
+}
+
+#[test]
+fn extract_macho_symbols() {
+    // `builtin_memmove` is in `__TEXT,__text_exec`. Data, private, local and undefined
+    // symbols are ignored.
+    for file in &["test_13.o", "test_13.dylib"] {
+        let symbols = extract_symbols(TESTS_DIR.join(file), &Default::default()).unwrap();
+        let mut names = symbols.names();
+        names.sort_unstable();
+        assert_eq!(names, ["builtin_memcpy", "builtin_memmove"]);
+    }
+
+    // The only code section is not the first one
+    let symbols = extract_symbols(TESTS_DIR.join("test_14.o"), &Default::default()).unwrap();
+    assert_eq!(symbols.names(), ["builtin_strcmp"]);
+}
+
+#[test]
+fn extract_pe_symbols() {
+    // Forwarded (`builtin_strlen`) and data (`builtin_table`) exports are ignored
+    let symbols = extract_symbols(TESTS_DIR.join("test_15.dll"), &Default::default()).unwrap();
+    let mut names = symbols.names();
+    names.sort_unstable();
+    assert_eq!(names, ["builtin_memcpy", "builtin_memmove"]);
+    assert!(symbols
+        .symbols
+        .iter()
+        .all(|symbol| symbol.table == SymbolTable::PeExports));
+
+    let symbols = extract_symbols(TESTS_DIR.join("test_15.obj"), &Default::default()).unwrap();
+    assert_eq!(
+        symbols.names(),
+        ["builtin_memcpy", "builtin_memmove", "caller"]
+    );
+    assert!(symbols
+        .symbols
+        .iter()
+        .all(|symbol| symbol.table == SymbolTable::Coff));
+
+    // Files that are not objects, including archives without objects, are not read as COFF
+    for path in &["test_9.h", "test_17.json", "test_24.a"] {
+        let err = extract_symbols(TESTS_DIR.join(path), &Default::default())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<WError>(),
+            Some(WError::Unsupported)
+        ));
+    }
+}
+
+#[test]
+fn wasm_builtins_library() {
+    let symbols = extract_symbols(TESTS_DIR.join("test_16.wasm"), &Default::default()).unwrap();
+    assert_eq!(symbols.names(), ["builtin_memcpy", "builtin_strcmp"]);
+    let signatures = symbols.signatures();
+    assert_eq!(
+        signatures["builtin_memcpy"].to_string(),
+        "(i32, i32, i32) -> (i32)"
+    );
+
+    // `builtin_strcmp` is an imported function with an extra parameter
+    let config = PatcherConfig {
+        builtins_libraries: vec![BuiltinsLibrary::new(TESTS_DIR.join("test_16.wasm"))],
+        builtins_signature_check: SignatureCheck::Warn,
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, TESTS_DIR.join("test_1.wasm")).unwrap();
+    assert_eq!(patcher.warnings().len(), 1);
+    assert!(patcher.warnings()[0].contains("builtin_strcmp"));
+    assert_eq!(patcher.patched_builtins_map("env").unwrap().len(), 2);
+}
+
+#[test]
+fn patch_with_manifest() {
+    let path_in = TESTS_DIR.join("test_1.wasm");
+    for manifest in &["test_17.toml", "test_17.json"] {
+        let config = PatcherConfig {
+            builtins_manifest_path: Some(TESTS_DIR.join(manifest)),
+            builtins_signature_check: SignatureCheck::Warn,
+            ..Default::default()
+        };
+        let patcher = Patcher::from_file(config, &path_in).unwrap();
+
+        // `builtin_strcmp` is expected to have an extra parameter
+        assert_eq!(patcher.warnings().len(), 1);
+        assert!(patcher.warnings()[0].contains("builtin_strcmp"));
+
+        let libc_map = patcher.patched_builtins_map("libc").unwrap();
+        assert_eq!(libc_map.len(), 1);
+        assert!(libc_map.contains_key("fast_memcpy"));
+        let env_map = patcher.patched_builtins_map("env").unwrap();
+        assert_eq!(env_map.len(), 1);
+
+        let module = patcher.patched_module();
+        let import_section = module.section(SectionId::Import).unwrap();
+        let imports: Vec<_> = ImportSectionReader::new(import_section.reader())
+            .unwrap()
+            .into_imports()
+            .take(2)
+            .map(|import| {
+                let import = import.unwrap();
+                (import.module, import.name)
+            })
+            .collect();
+        assert_eq!(
+            imports,
+            [("env", "builtin_strcmp"), ("libc", "fast_memcpy")]
+        );
+    }
+}
+
+#[test]
+fn extract_versioned_elf_symbols() {
+    let extract = |required_version: Option<&str>| {
+        let options = ExtractOptions {
+            include_static_symbols: true,
+            required_version: required_version.map(|version| version.to_string()),
+            ..Default::default()
+        };
+        let symbols = extract_symbols(TESTS_DIR.join("test_18.elf"), &options).unwrap();
+        let mut symbols: Vec<_> = symbols
+            .symbols
+            .into_iter()
+            .map(|symbol| (symbol.name, symbol.version.unwrap()))
+            .collect();
+        symbols.sort();
+        symbols
+    };
+    let versioned = |symbols: &[(&str, &str)]| -> Vec<(String, String)> {
+        symbols
+            .iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect()
+    };
+
+    // `builtin_memcpy` is defined both as `LIBB_1` and as the default `LIBB_2` version
+    assert_eq!(
+        extract(None),
+        versioned(&[
+            ("builtin_memcpy", "LIBB_2"),
+            ("builtin_memmove", "LIBB_2"),
+            ("builtin_strcmp", "LIBB_1"),
+        ])
+    );
+    assert_eq!(
+        extract(Some("LIBB_1")),
+        versioned(&[("builtin_memcpy", "LIBB_1"), ("builtin_strcmp", "LIBB_1")])
+    );
+    assert!(extract(Some("LIBB_3")).is_empty());
+}
+
+#[test]
+fn patch_with_several_libraries() {
+    let path_in = TESTS_DIR.join("test_1.wasm");
+    let config = PatcherConfig {
+        builtins_libraries: vec![
+            BuiltinsLibrary {
+                path: TESTS_DIR.join("test_10.elf"),
+                import_module: Some("fast".to_string()),
+            },
+            BuiltinsLibrary::new(TESTS_DIR.join("test_11.a")),
+        ],
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config, path_in).unwrap();
+
+    // `builtin_memcpy` and `builtin_memmove` are defined by both libraries
+    let warnings = patcher.warnings();
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings
+        .iter()
+        .all(|warning| warning.contains("using `") && warning.contains("test_10.elf")));
+
+    let fast_map = patcher.patched_builtins_map("fast").unwrap();
+    assert_eq!(fast_map.len(), 2);
+    assert!(fast_map.contains_key("builtin_memcpy"));
+    assert!(fast_map.contains_key("builtin_memmove"));
+    let env_map = patcher.patched_builtins_map("env").unwrap();
+    assert_eq!(env_map.len(), 1);
+    assert!(env_map.contains_key("builtin_strcmp"));
+}
+
+#[test]
+fn patch_with_linked_builtins() {
+    let path_in = TESTS_DIR.join("test_1.wasm");
+    let config = PatcherConfig {
+        builtins_link_path: Some(TESTS_DIR.join("test_19.wasm")),
+        builtins_additional: vec!["builtin_memmove".to_string()],
+        ..Default::default()
+    };
     let patcher = Patcher::from_file(config, path_in).unwrap();
-    let mut hasher = SipHasher13::new();
-    hasher.write(&patcher.into_bytes().unwrap());
-    assert_eq!(hasher.finish(), 13205801729184435761);
+
+    // Only `builtin_memmove` is imported, linked builtins don't need any import
+    let env_map = patcher.patched_builtins_map("env").unwrap();
+    assert_eq!(env_map.len(), 1);
+    assert!(env_map.contains_key("builtin_memmove"));
+
+    let bytes = patcher.into_bytes().unwrap();
+    Validator::new_with_features(WasmFeatures::all())
+        .validate_all(&bytes)
+        .unwrap();
+    let module = Module::from_bytes(&bytes).unwrap();
+
+    // `builtin_memcpy`, `builtin_strcmp` and the function they both call are copied, along
+    // with the globals and data segments they use
+    let count = |id: SectionId| module.section(id).unwrap().reader().read_var_u32().unwrap();
+    assert_eq!(count(SectionId::Function), 9);
+    assert_eq!(count(SectionId::Global), 3);
+    assert_eq!(count(SectionId::Data), 2);
+    assert_eq!(count(SectionId::DataCount), 2);
+
+    // The entrypoint calls `strcmp` and `memmove`, redirected to the linked function and the
+    // import
+    let names: HashMap<_, _> = crate::functions_names::function_names(&module)
+        .unwrap()
+        .into_iter()
+        .collect();
+    let calls: Vec<_> = function_body_operators(&module, 0)
+        .into_iter()
+        .filter_map(|operator| match operator {
+            Operator::Call { function_index } => Some(names[&function_index].clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(calls, ["builtin_strcmp", "builtin_memmove"]);
+
+    // `builtin_strcmp` is imported by this module, so it cannot be copied
+    let config = PatcherConfig {
+        builtins_link_path: Some(TESTS_DIR.join("test_16.wasm")),
+        ..Default::default()
+    };
+    let err = Patcher::from_file(config, TESTS_DIR.join("test_1.wasm"))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("imported"));
+
+    // The data used by `builtin_strcmp` is past the single page of this module
+    let bytes = wat::parse_file(TESTS_DIR.join("test_22.wat")).unwrap();
+    let config = PatcherConfig {
+        builtins_link_path: Some(TESTS_DIR.join("test_19.wasm")),
+        ..Default::default()
+    };
+    let err = Patcher::from_bytes(config, &bytes).err().unwrap();
+    assert!(err.to_string().contains("initial memory"));
+}
+
+#[test]
+fn patch_with_linked_stack_frame() {
+    // `builtin_sum3` was compiled from C, and keeps an array on the shadow stack:
+    //
+    //   static __attribute__((noinline)) int sum(const int *values, int count);
+    //   int builtin_sum3(int a, int b, int c) {
+    //       int values[3] = { a, b, c };
+    //       return sum(values, 3);
+    //   }
+    let bytes = wat::parse_file(TESTS_DIR.join("test_26.wat")).unwrap();
+    let config = PatcherConfig {
+        builtins_link_path: Some(TESTS_DIR.join("test_25.wasm")),
+        ..Default::default()
+    };
+    let bytes = Patcher::from_bytes(config.clone(), &bytes)
+        .unwrap()
+        .into_bytes()
+        .unwrap();
+    validate(&bytes);
+    let module = Module::from_bytes(&bytes).unwrap();
+
+    // The stack pointer of the builtins module is not copied, the builtin moves the stack
+    // pointer of the module instead
+    let count = |id: SectionId| module.section(id).unwrap().reader().read_var_u32().unwrap();
+    assert_eq!(count(SectionId::Global), 2);
+    assert_eq!(count(SectionId::Function), 4);
+    assert_eq!(called_functions(&module, 1), [2]);
+    let globals: Vec<_> = function_body_operators(&module, 2)
+        .into_iter()
+        .filter_map(|operator| match operator {
+            Operator::GlobalGet { global_index } | Operator::GlobalSet { global_index } => {
+                Some(global_index)
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(globals, [1, 1, 1]);
+
+    // This module has no stack pointer to share
+    let bytes = wat::parse_str(
+        r#"(module
+             (memory 1)
+             (func (export "sum3") (param i32 i32 i32) (result i32)
+               local.get 0))"#,
+    )
+    .unwrap();
+    let err = Patcher::from_bytes(config, &bytes).err().unwrap();
+    assert!(err.to_string().contains("stack pointer"));
+}
+
+#[test]
+fn patch_and_remove_dead_code() {
+    let bytes = wat::parse_file(TESTS_DIR.join("test_20.wat")).unwrap();
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        remove_dead_code: true,
+        ..Default::default()
+    };
+    let patcher = Patcher::from_bytes(config, &bytes).unwrap();
+
+    // `memcpy` is replaced, and `helper` was only called by it
+    let report = patcher.dead_code_report().unwrap();
+    assert_eq!(report.functions_removed, 3);
+    assert_eq!(report.types_removed, 2);
+    assert!(report.bytes_saved > 0);
+
+    let bytes = patcher.into_bytes().unwrap();
+    Validator::new_with_features(WasmFeatures::all())
+        .validate_all(&bytes)
+        .unwrap();
+    let module = Module::from_bytes(&bytes).unwrap();
+    let names: Vec<_> = crate::functions_names::function_names(&module)
+        .unwrap()
+        .into_iter()
+        .map(|(_, name)| name)
+        .collect();
+    assert_eq!(names, ["builtin_memcpy", "main", "indirect"]);
+    assert!(function_body_operators(&module, 0).contains(&Operator::Call { function_index: 0 }));
+    let exports: Vec<_> = crate::patcher::exported_functions(&module).unwrap();
+    assert_eq!(exports, [(1, "main".to_string())]);
+}
+
+#[test]
+fn unpatch_removed_dead_code() {
+    let bytes = wat::parse_file(TESTS_DIR.join("test_20.wat")).unwrap();
+    let path = |extension: &str| {
+        env::temp_dir().join(format!(
+            "wasmonkey-unpatch-dead-code-{}.{}",
+            process::id(),
+            extension
+        ))
+    };
+    let (patched_path, builtins_map_path) = (path("wasm"), path("json"));
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        builtins_map_path: Some(builtins_map_path.clone()),
+        remove_dead_code: true,
+        ..Default::default()
+    };
+    let patcher = Patcher::from_bytes(config.clone(), &bytes).unwrap();
+    patcher.store_to_file(&patched_path).unwrap();
+
+    // The replaced functions are gone, so the module cannot be unpatched
+    let err = Unpatcher::from_file(config, &patched_path).err().unwrap();
+    fs::remove_file(&patched_path).unwrap();
+    fs::remove_file(&builtins_map_path).unwrap();
+    match err.downcast_ref::<WError>() {
+        Some(WError::UsageError(message)) => assert!(message.contains("dead code")),
+        _ => panic!("Unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn unpatch() {
+    let path_in = TESTS_DIR.join("test_1.wasm");
+    let original = fs::read(&path_in).unwrap();
+    for builtins_map_original_names in &[false, true] {
+        let path = |extension: &str| {
+            env::temp_dir().join(format!(
+                "wasmonkey-unpatch-{}-{}.{}",
+                process::id(),
+                builtins_map_original_names,
+                extension
+            ))
+        };
+        let (patched_path, builtins_map_path) = (path("wasm"), path("json"));
+        let config = PatcherConfig {
+            builtins_additional: vec!["builtin_memmove".to_string(), "builtin_strcmp".to_string()],
+            builtins_import_modules: vec![("memmove".to_string(), "libc".to_string())]
+                .into_iter()
+                .collect(),
+            builtins_map_path: Some(builtins_map_path.clone()),
+            builtins_map_original_names: *builtins_map_original_names,
+            ..Default::default()
+        };
+        let patcher = Patcher::from_file(config.clone(), &path_in).unwrap();
+        patcher.store_to_file(&patched_path).unwrap();
+
+        let unpatcher = Unpatcher::from_file(config, &patched_path).unwrap();
+        fs::remove_file(&patched_path).unwrap();
+        fs::remove_file(&builtins_map_path).unwrap();
+        assert_eq!(unpatcher.into_bytes().unwrap(), original);
+    }
+}
+
+#[test]
+fn unpatch_with_provenance() {
+    let path_in = TESTS_DIR.join("test_1.wasm");
+    let original = fs::read(&path_in).unwrap();
+    let path = |extension: &str| {
+        env::temp_dir().join(format!(
+            "wasmonkey-unpatch-provenance-{}.{}",
+            process::id(),
+            extension
+        ))
+    };
+    let (patched_path, builtins_map_path) = (path("wasm"), path("json"));
+    let config = PatcherConfig {
+        builtins_additional: vec!["host_memmove".to_string()],
+        builtins_rename_rules: RenameRules {
+            prefix: Some("host_".to_string()),
+            ..Default::default()
+        },
+        builtins_map_path: Some(builtins_map_path.clone()),
+        ..Default::default()
+    };
+    let patcher = Patcher::from_file(config.clone(), &path_in).unwrap();
+    patcher.store_to_file(&patched_path).unwrap();
+
+    // The replaced functions are recorded in the `wasmonkey` section, so the rename rules
+    // don't have to be given again
+    let config = PatcherConfig {
+        builtins_map_path: Some(builtins_map_path.clone()),
+        ..Default::default()
+    };
+    let unpatcher = Unpatcher::from_file(config, &patched_path).unwrap();
+    fs::remove_file(&patched_path).unwrap();
+    fs::remove_file(&builtins_map_path).unwrap();
+    assert_eq!(unpatcher.into_bytes().unwrap(), original);
+}
+
+#[test]
+fn patch_twice() {
+    let path_in = TESTS_DIR.join("test_1.wasm");
+    let config = |builtins: &[&str]| PatcherConfig {
+        builtins_additional: builtins.iter().map(|name| name.to_string()).collect(),
+        ..Default::default()
+    };
+    let patched = Patcher::from_file(config(&["builtin_memmove"]), path_in)
+        .unwrap()
+        .into_bytes()
+        .unwrap();
+    let provenance = Provenance::from_module(&Module::from_bytes(&patched).unwrap())
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        provenance.builtins,
+        [PatchedBuiltin {
+            name: "memmove".to_string(),
+            import_module: Some("env".to_string()),
+            import_name: "builtin_memmove".to_string(),
+            existing_import: false,
+            kept_references: vec![],
+        }]
+    );
+
+    // Already patched functions are left untouched
+    let patcher = Patcher::from_bytes(config(&["builtin_memmove"]), &patched).unwrap();
+    assert_eq!(patcher.into_bytes().unwrap(), patched);
+
+    // Only new builtins are applied
+    let patcher =
+        Patcher::from_bytes(config(&["builtin_memmove", "builtin_strcmp"]), &patched).unwrap();
+    assert_eq!(patcher.patched_builtins_map("env").unwrap().len(), 2);
+    let module = patcher.patched_module();
+    let import_section = module.section(SectionId::Import).unwrap();
+    let imports: Vec<_> = ImportSectionReader::new(import_section.reader())
+        .unwrap()
+        .into_imports()
+        .map(|import| import.unwrap().name)
+        .collect();
+    assert_eq!(imports, ["builtin_strcmp", "builtin_memmove"]);
+    let updated_provenance = Provenance::from_module(&module).unwrap().unwrap();
+    assert_eq!(updated_provenance.input_hash, provenance.input_hash);
+    assert_eq!(updated_provenance.builtins.len(), 2);
+}
+
+#[test]
+fn patch_with_existing_imports() {
+    let bytes = wat::parse_file(TESTS_DIR.join("test_21.wat")).unwrap();
+    let config = |builtins: &[&str]| PatcherConfig {
+        builtins_additional: builtins.iter().map(|name| name.to_string()).collect(),
+        ..Default::default()
+    };
+
+    // `builtin_memcpy` is already imported with the right type
+    let patcher =
+        Patcher::from_bytes(config(&["builtin_memcpy", "builtin_memmove"]), &bytes).unwrap();
+    assert_eq!(patcher.patched_builtins_map("env").unwrap().len(), 1);
+    let bytes = patcher.into_bytes().unwrap();
+    Validator::new_with_features(WasmFeatures::all())
+        .validate_all(&bytes)
+        .unwrap();
+    let module = Module::from_bytes(&bytes).unwrap();
+    let import_section = module.section(SectionId::Import).unwrap();
+    assert_eq!(
+        ImportSectionReader::new(import_section.reader())
+            .unwrap()
+            .into_imports()
+            .count(),
+        2
+    );
+    assert!(function_body_operators(&module, 2).contains(&Operator::Call { function_index: 0 }));
+
+    // `builtin_strcmp` is imported with an extra parameter
+    let bytes = wat::parse_file(TESTS_DIR.join("test_21.wat")).unwrap();
+    let err = Patcher::from_bytes(config(&["builtin_strcmp"]), &bytes)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("already imported"));
+}
+
+#[test]
+fn unpatch_existing_imports() {
+    let bytes = wat::parse_file(TESTS_DIR.join("test_21.wat")).unwrap();
+    let path = |extension: &str| {
+        env::temp_dir().join(format!(
+            "wasmonkey-unpatch-existing-{}.{}",
+            process::id(),
+            extension
+        ))
+    };
+    let (patched_path, builtins_map_path) = (path("wasm"), path("json"));
+    let config = PatcherConfig {
+        builtins_additional: vec!["builtin_memcpy".to_string()],
+        builtins_map_path: Some(builtins_map_path.clone()),
+        ..Default::default()
+    };
+    let patcher = Patcher::from_bytes(config.clone(), &bytes).unwrap();
+    patcher.store_to_file(&patched_path).unwrap();
+
+    // The call to the import is kept, the call to `memcpy` is restored
+    let unpatcher = Unpatcher::from_file(config, &patched_path).unwrap();
+    fs::remove_file(&patched_path).unwrap();
+    fs::remove_file(&builtins_map_path).unwrap();
+    assert_eq!(unpatcher.into_bytes().unwrap(), bytes);
 }
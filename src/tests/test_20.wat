@@ -0,0 +1,17 @@
+(module
+  (table 1 funcref)
+  (elem (i32.const 0) $indirect)
+  (func $helper (param f32) (result f32)
+    local.get 0)
+  (func $memcpy (export "memcpy") (param i32 i32 i32) (result i32)
+    f32.const 1
+    call $helper
+    drop
+    local.get 0)
+  (func $main (export "main") (param i32) (result i32)
+    local.get 0
+    local.get 0
+    local.get 0
+    call $memcpy)
+  (func $indirect)
+  (func $unused (param i64)))
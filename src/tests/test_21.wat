@@ -0,0 +1,17 @@
+(module
+  (import "env" "builtin_memcpy" (func $builtin_memcpy (param i32 i32 i32) (result i32)))
+  (import "env" "builtin_strcmp" (func $builtin_strcmp (param i32 i32 i32) (result i32)))
+  (func $memcpy (export "memcpy") (param i32 i32 i32) (result i32)
+    local.get 0)
+  (func $strcmp (export "strcmp") (param i32 i32) (result i32)
+    i32.const 0)
+  (func $main (export "main") (param i32) (result i32)
+    local.get 0
+    local.get 0
+    local.get 0
+    call $builtin_memcpy
+    local.get 0
+    local.get 0
+    call $memcpy
+    local.get 0
+    call $strcmp))
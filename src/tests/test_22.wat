@@ -0,0 +1,8 @@
+(module
+  (memory 1)
+  (func $strcmp (export "strcmp") (param i32 i32) (result i32)
+    i32.const 0)
+  (func $main (export "main") (param i32) (result i32)
+    local.get 0
+    local.get 0
+    call $strcmp))
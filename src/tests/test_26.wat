@@ -0,0 +1,12 @@
+(module
+  (memory 2)
+  (global $counter (mut i32) (i32.const 0))
+  (global $__stack_pointer (mut i32) (i32.const 65536))
+  (data (i32.const 65536) "wasmonkey")
+  (func $sum3 (export "sum3") (param i32 i32 i32) (result i32)
+    local.get 0)
+  (func $main (export "main") (param i32) (result i32)
+    local.get 0
+    local.get 0
+    local.get 0
+    call $sum3))
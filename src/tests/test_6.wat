@@ -0,0 +1,21 @@
+(module
+  (type $copy_t (func (param i32 i32 i32) (result i32)))
+  (memory 1)
+  (export "memory" (memory 0))
+  (export "memcpy" (func $memcpy))
+  (export "copy" (func $copy))
+  (export "copy_ref" (func $copy_ref))
+  (elem declare func $memcpy)
+  (func $memcpy (type $copy_t) (param i32 i32 i32) (result i32)
+    local.get 0)
+  (func $copy (type $copy_t) (param i32 i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    local.get 2
+    return_call $memcpy)
+  (func $copy_ref (type $copy_t) (param i32 i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    local.get 2
+    ref.func $memcpy
+    call_ref $copy_t))
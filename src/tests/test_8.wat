@@ -0,0 +1,8 @@
+(module
+  (func $memcpy (param i32 i32 i32) (result i32)
+    local.get 0)
+  (func $run (result i32)
+    i32.const 0
+    i32.const 0
+    i32.const 0
+    call $memcpy))
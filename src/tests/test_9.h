@@ -0,0 +1,5 @@
+#include <stddef.h>
+
+/* Builtins used by the signature checks */
+void *builtin_memcpy(void *dst, const void *src, size_t n);
+int builtin_strcmp(const char *a, const char *b, size_t n);
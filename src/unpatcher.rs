@@ -0,0 +1,233 @@
+use std::fs;
+use std::path::Path;
+
+use wasm_encoder::reencode::{self, Reencode};
+use wasm_encoder::{ImportSection, SectionId};
+use wasmparser::{ImportSectionReader, TypeRef};
+
+use crate::debug_info;
+use crate::errors::*;
+use crate::functions_ids::*;
+use crate::functions_names::*;
+use crate::linking::is_relocatable;
+use crate::map::*;
+use crate::patcher::{exported_functions, PatcherConfig};
+use crate::provenance::{Provenance, PROVENANCE_SECTION_NAME};
+use crate::sections::*;
+
+/// Reverts the substitutions listed in a builtins map: builtins imports are removed, and
+/// calls are restored to the original functions.
+pub struct Unpatcher {
+    pub config: PatcherConfig,
+    unpatched_module: Module,
+}
+
+impl Unpatcher {
+    /// `config` is the configuration the module was patched with; its builtins map is read.
+    pub fn new(config: PatcherConfig, module: Module) -> Result<Self, Error> {
+        let builtins_map_path = config
+            .builtins_map_path
+            .as_ref()
+            .ok_or(WError::UsageError("Unpatching requires a builtins map"))?;
+        let builtins_map = PatchedBuiltinsMap::from_file(builtins_map_path)?;
+        let unpatched_module = unpatch_module(module, &config, &builtins_map)?;
+        let unpatcher = Unpatcher {
+            config,
+            unpatched_module,
+        };
+        Ok(unpatcher)
+    }
+
+    pub fn from_bytes(config: PatcherConfig, bytes: &[u8]) -> Result<Self, Error> {
+        let module = Module::from_bytes(bytes)?;
+        Self::new(config, module)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(config: PatcherConfig, path_in: P) -> Result<Self, Error> {
+        let bytes = fs::read(path_in)?;
+        Self::from_bytes(config, &bytes)
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>, WError> {
+        Ok(self.unpatched_module.to_bytes())
+    }
+
+    pub fn store_to_file<P: AsRef<Path>>(self, path_out: P) -> Result<(), WError> {
+        fs::write(path_out, self.unpatched_module.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn unpatched_module(self) -> Module {
+        self.unpatched_module
+    }
+}
+
+/// Finds the function replaced by a builtin, by the name recorded in the provenance section
+/// if the module has one, by original name if the map has it, or else by applying the rename
+/// rules to the names of the functions.
+fn find_original_function(
+    named_functions: &[(u32, String)],
+    config: &PatcherConfig,
+    provenance_name: Option<&str>,
+    name: &str,
+    import_name: &str,
+) -> Option<u32> {
+    provenance_name
+        .and_then(|provenance_name| {
+            named_functions
+                .iter()
+                .find(|(_, function_name)| function_name == provenance_name)
+        })
+        .or_else(|| {
+            named_functions
+                .iter()
+                .find(|(_, function_name)| function_name == name && name != import_name)
+        })
+        .or_else(|| {
+            named_functions.iter().find(|(_, function_name)| {
+                config.builtins_rename_rules.import_name(function_name) == import_name
+            })
+        })
+        .map(|(function_id, _)| *function_id)
+}
+
+fn remove_imports(module: &mut Module, removed: &[u32]) -> Result<(), Error> {
+    let import_section_idx = module
+        .sections()
+        .iter()
+        .position(|section| section.is(SectionId::Import))
+        .expect("No import section");
+    let mut import_section = ImportSection::new();
+    let mut function_id = 0;
+    for import in
+        ImportSectionReader::new(module.sections()[import_section_idx].reader())?.into_imports()
+    {
+        let import = import?;
+        if let TypeRef::Func(_) | TypeRef::FuncExact(_) = import.ty {
+            function_id += 1;
+            if removed.contains(&(function_id - 1)) {
+                continue;
+            }
+        }
+        let entity_type = reencode::RoundtripReencoder.entity_type(import.ty)?;
+        import_section.import(import.module, import.name, entity_type);
+    }
+    if import_section.is_empty() {
+        module.sections_mut().remove(import_section_idx);
+    } else {
+        module.sections_mut()[import_section_idx] = Section::from_encoded(&import_section)?;
+    }
+    Ok(())
+}
+
+fn unpatch_module(
+    module: Module,
+    config: &PatcherConfig,
+    builtins_map: &PatchedBuiltinsMap,
+) -> Result<Module, Error> {
+    let mut module = module;
+    if is_relocatable(&module) {
+        bail!(WError::UsageError(
+            "Relocatable objects cannot be unpatched"
+        ));
+    }
+    let provenance = Provenance::from_module(&module)?;
+    if provenance
+        .as_ref()
+        .is_some_and(|provenance| provenance.dead_code_removed)
+    {
+        bail!(WError::UsageError(
+            "The functions replaced by builtins were removed as dead code, the module cannot be unpatched"
+        ));
+    }
+
+    let imports = function_imports(&module)?;
+    let imports_count = imports.len() as u32;
+    let mut named_functions = exported_functions(&module)?;
+    named_functions.extend(function_names(&module)?);
+    // Functions may have been matched by their name in the debugging information
+    named_functions.extend(debug_info::function_names(&module)?);
+    named_functions.retain(|(function_id, _)| *function_id >= imports_count);
+
+    let mut restored = vec![];
+    let mut restored_imports = vec![];
+    for (import_module, map) in &builtins_map.modules {
+        for (name, import_name) in map {
+            let import_function_id = match imports
+                .iter()
+                .position(|(module, name, _)| module == import_module && name == import_name)
+            {
+                None => continue,
+                Some(import_function_id) => import_function_id as u32,
+            };
+            let provenance_name = provenance.as_ref().and_then(|provenance| {
+                provenance
+                    .builtins
+                    .iter()
+                    .rev()
+                    .find(|builtin| {
+                        builtin.import_module.as_ref() == Some(import_module)
+                            && &builtin.import_name == import_name
+                    })
+                    .map(|builtin| builtin.name.as_str())
+            });
+            let original_function_id = find_original_function(
+                &named_functions,
+                config,
+                provenance_name,
+                name,
+                import_name,
+            )
+            .ok_or(WError::UsageError(
+                "The function replaced by a builtin was not found",
+            ))?;
+            let kept_references = builtins_map.existing_import(import_module, import_name);
+            restored.push((import_function_id, original_function_id, kept_references));
+            restored_imports.push((import_module.as_str(), import_name.as_str()));
+        }
+    }
+
+    // Imports the module already had are kept along with their own references
+    let functions_count = functions_space(&module)? as usize;
+    let mut kept = vec![true; functions_count];
+    for (import_function_id, _, kept_references) in &restored {
+        kept[*import_function_id as usize] = kept_references.is_some();
+    }
+    let mut function_ids_map = FunctionIdsMap::compact(&kept);
+    for (import_function_id, original_function_id, kept_references) in &restored {
+        let original_function_id = function_ids_map.function_id(*original_function_id);
+        function_ids_map.replace(*import_function_id, original_function_id);
+        if let Some(kept_references) = kept_references {
+            function_ids_map.keep_references(*import_function_id, kept_references.to_vec());
+        }
+    }
+    remap_function_ids(&mut module, &function_ids_map)?;
+    let removed: Vec<_> = restored
+        .iter()
+        .filter(|(_, _, kept_references)| kept_references.is_none())
+        .map(|(import_function_id, _, _)| *import_function_id)
+        .collect();
+    if !removed.is_empty() {
+        remove_imports(&mut module, &removed)?;
+    }
+
+    if let Some(mut provenance) = provenance {
+        provenance.builtins.retain(|builtin| {
+            let import = (
+                builtin.import_module.as_deref(),
+                builtin.import_name.as_str(),
+            );
+            !restored_imports
+                .iter()
+                .any(|(import_module, import_name)| import == (Some(*import_module), *import_name))
+        });
+        if provenance.builtins.is_empty() {
+            module
+                .sections_mut()
+                .retain(|section| !section.is_custom(PROVENANCE_SECTION_NAME));
+        } else {
+            provenance.store(&mut module)?;
+        }
+    }
+    Ok(module)
+}